@@ -1,4 +1,4 @@
-use candid::Deserialize;
+use candid::{CandidType, Deserialize};
 use serde;
 
 pub type Timestamp = u64;
@@ -21,36 +21,28 @@ pub struct OpenAIResultChoices {
     pub finish_reason: String,
 }
 
+// content is null when the model calls a tool instead of answering
 #[derive(serde::Serialize, Deserialize)]
 pub struct OpenAIResultMessage {
     pub role: String,
-    pub content: String,
-}
-
-#[derive(serde::Serialize, Deserialize)]
-pub struct OpenAIResultMessageContent {
-    pub thoughts: OpenAIResultMessageContentThoughts,
-    pub command: OpenAIResultMessageContentCommand,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Option<Vec<OpenAIToolCall>>,
 }
 
 #[derive(serde::Serialize, Deserialize)]
-pub struct OpenAIResultMessageContentThoughts {
-    pub text: String,
-    pub reasoning: String,
-    pub plan: String,
-    pub criticism: String,
-    pub speak: String,
+pub struct OpenAIToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub call_type: String,
+    pub function: OpenAIToolCallFunction,
 }
 
 #[derive(serde::Serialize, Deserialize)]
-pub struct OpenAIResultMessageContentCommand {
+pub struct OpenAIToolCallFunction {
     pub name: String,
-    pub args: serde_json::Value,
-}
-
-#[derive(serde::Serialize, Deserialize)]
-pub struct OpenAIResultMessageContentCommandArgs {
-    pub query: String,
+    pub arguments: String,
 }
 
 #[derive(serde::Serialize, Deserialize)]
@@ -80,3 +72,129 @@ pub struct OpenAIEmbeddingData {
     pub index: u8,
     pub embedding: Embeddings,
 }
+
+// Tokens, HTTP outcalls and cycles spent by the brain on a request, including retries
+#[derive(CandidType, serde::Serialize, Deserialize, Default, Clone)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub http_outcalls: u64,
+    // approximate, read from the balance which concurrent requests also spend
+    pub cycles_used: u64,
+}
+
+#[derive(serde::Serialize, Deserialize)]
+pub struct AnthropicResult {
+    pub id: String,
+    pub model: String,
+    pub content: Vec<AnthropicContentBlock>,
+    pub stop_reason: Option<String>,
+    pub usage: AnthropicUsage,
+}
+
+// text blocks have text, tool_use blocks have the name and input of the called tool
+#[derive(serde::Serialize, Deserialize)]
+pub struct AnthropicContentBlock {
+    #[serde(rename = "type")]
+    pub block_type: String,
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub input: Option<serde_json::Value>,
+}
+
+#[derive(serde::Serialize, Deserialize)]
+pub struct AnthropicUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+// Tool called by the model, arguments is the JSON object string of the call
+#[derive(serde::Serialize, Deserialize, Clone)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+// Chat completion content, tool call and token usage, as returned by the transform of a provider
+#[derive(serde::Serialize, Deserialize)]
+pub struct ChatCompletion {
+    pub content: String,
+    #[serde(default)]
+    pub tool_call: Option<ToolCall>,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+// Request shape, transform and usage of a chat API.
+// OpenAI also covers OpenAI-compatible servers such as vLLM and Ollama.
+#[derive(CandidType, serde::Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ProviderKind {
+    OpenAI,
+    Anthropic,
+}
+
+// Provider of the models whose name starts with one of model_prefixes.
+// url is the full chat endpoint of a proxy in front of the provider, e.g.
+// https://anthropic-proxy.example.com/v1/messages. Each replica makes the HTTPS outcall,
+// so the proxy must answer requests of the same idempotency-key with the same response.
+#[derive(CandidType, serde::Serialize, Deserialize, Clone)]
+pub struct LlmProvider {
+    pub name: String,
+    pub kind: ProviderKind,
+    pub url: String,
+    pub api_key: String,
+    pub model_prefixes: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AskResult {
+    pub content: String,
+    pub usage: Usage,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EmbeddingsResult {
+    pub embeddings: Embeddings,
+    pub usage: Usage,
+}
+
+// Command offered to the model as a function to call, parameters is the JSON schema of its args
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CommandSchema {
+    pub name: String,
+    pub description: String,
+    pub parameters: String,
+}
+
+#[derive(CandidType, serde::Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Thoughts {
+    pub text: String,
+    pub reasoning: String,
+    pub plan: String,
+    pub criticism: String,
+    pub speak: String,
+}
+
+// args is the JSON object of the command arguments
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Command {
+    pub name: String,
+    pub args: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AgentResponse {
+    pub thoughts: Thoughts,
+    pub command: Command,
+}
+
+// response is an error with the answer of the model when it did not call a command
+#[derive(CandidType, Deserialize)]
+pub struct ThinkResult {
+    pub response: Result<AgentResponse, String>,
+    pub usage: Usage,
+}
@@ -17,19 +17,30 @@ use serde::Serialize;
 use serde_json::json;
 
 mod datatype;
-use datatype::{Embeddings, OpenAIEmbeddingResult, OpenAIResult};
+use datatype::{
+    AgentResponse, AskResult, ChatCompletion, CommandSchema, Embeddings, EmbeddingsResult,
+    LlmProvider, OpenAIEmbeddingResult, ProviderKind, ThinkResult, Usage,
+};
 
 mod guards;
 use async_recursion::async_recursion;
 use guards::assert_owner;
 
+mod toolcall;
+use toolcall::parse_tool_call;
+
+mod provider;
+use provider::{
+    create_default_provider, create_provider_header, create_request_body, find_provider,
+    parse_anthropic_response, parse_openai_response, validate_provider,
+};
+
 mod tokenutil;
 use tokenutil::{truncate_question, MAX_128K_TOKENS};
 
 mod httputil;
 use httputil::{
     create_header, generate_request_id, OPENAI_EMBEDDINGS_HOST, OPENAI_EMBEDDINGS_MODEL,
-    OPENAI_HOST,
 };
 
 #[derive(Default, CandidType, Serialize, Deserialize)]
@@ -39,6 +50,9 @@ pub struct State {
     pub gpt_model: String,
     pub battery_api_key: Option<String>,
     pub battery_canister: Option<Principal>,
+    // models without a provider are served by the OpenAI proxy
+    #[serde(default)]
+    pub llm_providers: Vec<LlmProvider>,
 }
 
 // Mutable global state
@@ -62,13 +76,98 @@ const CYCLES_TOPUP_GROUP: &str = "arcmindai_brain";
 // entry function for user to ask questions
 #[update(guard = "assert_owner")]
 #[candid_method(update)]
-#[async_recursion]
 async fn ask(
     question: String,
     custom_gpt_model: Option<String>,
     num_retries: i8,
     opt_request_id: Option<String>,
 ) -> String {
+    ask_with_usage(
+        question,
+        custom_gpt_model,
+        num_retries,
+        opt_request_id,
+        None,
+    )
+    .await
+    .content
+}
+
+// same as ask, also returning the tokens, HTTP outcalls and cycles spent on the answer
+// with an optional temperature instead of GPT_TEMPERATURE
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn ask_with_usage(
+    question: String,
+    custom_gpt_model: Option<String>,
+    num_retries: i8,
+    opt_request_id: Option<String>,
+    opt_temperature: Option<f32>,
+) -> AskResult {
+    let (completion, usage) = request_chat_completion(
+        question,
+        custom_gpt_model,
+        None,
+        num_retries,
+        opt_request_id,
+        opt_temperature,
+    )
+    .await;
+
+    AskResult {
+        content: match completion {
+            Ok(completion) => completion.content,
+            Err(message) => message,
+        },
+        usage,
+    }
+}
+
+// entry function for the agent to decide its next command, calling one of the given commands
+// with its thoughts instead of answering in free-form JSON
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn ask_with_commands(
+    question: String,
+    commands: Vec<CommandSchema>,
+    custom_gpt_model: Option<String>,
+    num_retries: i8,
+    opt_request_id: Option<String>,
+    opt_temperature: Option<f32>,
+) -> ThinkResult {
+    let (completion, usage) = request_chat_completion(
+        question,
+        custom_gpt_model,
+        Some(commands),
+        num_retries,
+        opt_request_id,
+        opt_temperature,
+    )
+    .await;
+
+    let response: Result<AgentResponse, String> = match completion {
+        Ok(ChatCompletion {
+            tool_call: Some(tool_call),
+            ..
+        }) => parse_tool_call(&tool_call),
+        Ok(completion) => Err(completion.content),
+        Err(message) => Err(message),
+    };
+
+    ThinkResult { response, usage }
+}
+
+#[async_recursion]
+async fn request_chat_completion(
+    question: String,
+    custom_gpt_model: Option<String>,
+    commands: Option<Vec<CommandSchema>>,
+    num_retries: i8,
+    opt_request_id: Option<String>,
+    opt_temperature: Option<f32>,
+) -> (Result<ChatCompletion, String>, Usage) {
+    let start_cycles_balance: u64 = ic_cdk::api::canister_balance();
+
     // use custom gpt model if provided
     let gpt_model = match custom_gpt_model {
         Some(model) => model,
@@ -87,66 +186,104 @@ async fn ask(
 
     let safe_question = truncate_question(question.clone(), max_token_limit);
 
+    let provider: LlmProvider = STATE.with(|state| {
+        let state = state.borrow();
+        find_provider(&state.llm_providers, &gpt_model)
+            .cloned()
+            .unwrap_or_else(|| create_default_provider(state.openai_api_key.clone()))
+    });
+
     // lower temperature = more predictable and deterministic response = less creative
     // so that IC replicas can reach consensus on the response
-    let request_body = json!({
-        "model": gpt_model,
-        "messages": [
-            {
-                "role": "user",
-                "content": safe_question
-            }
-        ],
-        "temperature": GPT_TEMPERATURE
-    });
+    let request_body = match create_request_body(
+        provider.kind,
+        &gpt_model,
+        &safe_question,
+        opt_temperature.unwrap_or(GPT_TEMPERATURE),
+        commands.as_deref(),
+    ) {
+        Ok(request_body) => request_body,
+        Err(message) => return (Err(message), Usage::default()),
+    };
 
     let json_utf8: Vec<u8> = request_body.to_string().into_bytes();
     let request_body_json: Option<Vec<u8>> = Some(json_utf8);
     let request_id = generate_request_id(opt_request_id.clone());
+    let headers = create_provider_header(&provider, request_id.clone());
 
-    // add requestId to OPENAI_URL
-    let openai_url = "https://".to_string() + OPENAI_HOST;
-    let openai_api_key = STATE.with(|state| (*state.borrow()).openai_api_key.clone());
-    let headers = create_header(openai_api_key, OPENAI_HOST.to_string(), request_id.clone());
+    let transform = match provider.kind {
+        ProviderKind::OpenAI => TransformContext::new(transform_openai_chat_completion, vec![]),
+        ProviderKind::Anthropic => TransformContext::new(transform_anthropic_messages, vec![]),
+    };
 
     let request = CanisterHttpRequestArgument {
-        url: openai_url.to_string(),
+        url: provider.url.clone(),
         max_response_bytes: Some(2000000),
         method: HttpMethod::POST,
         headers: headers,
         body: request_body_json,
-        transform: Some(TransformContext::new(
-            transform_openai_chat_completion,
-            vec![],
-        )),
+        transform: Some(transform),
     };
 
     match http_request(request).await {
         Ok((response,)) => {
             let result = String::from_utf8(response.body)
                 .expect("Transformed response is not UTF-8 encoded.");
-            result
+
+            // error messages of the transform are returned as they are, without usage
+            let completion: Result<ChatCompletion, String> =
+                serde_json::from_str(&result).map_err(|_| result);
+            let usage = Usage {
+                prompt_tokens: completion.as_ref().map_or(0, |c| c.prompt_tokens),
+                completion_tokens: completion.as_ref().map_or(0, |c| c.completion_tokens),
+                http_outcalls: 1,
+                cycles_used: get_cycles_used_since(start_cycles_balance),
+            };
+            (completion, usage)
         }
         Err((r, m)) => {
             if num_retries < MAX_NUM_RETIRES {
                 ic_cdk::println!("Retrying ask, num_retries: {}", num_retries);
-                return ask(
+                let (completion, mut usage) = request_chat_completion(
                     question.clone(),
                     Some(gpt_model),
+                    commands,
                     num_retries + 1,
                     Some(request_id),
+                    opt_temperature,
                 )
                 .await;
+                usage.http_outcalls += 1;
+                usage.cycles_used = get_cycles_used_since(start_cycles_balance);
+                return (completion, usage);
             }
 
             let message = format!("The ask resulted into error. RejectionCode: {r:?}, Error: {m}");
-            message
+            let usage = Usage {
+                http_outcalls: 1,
+                cycles_used: get_cycles_used_since(start_cycles_balance),
+                ..Default::default()
+            };
+            (Err(message), usage)
         }
     }
 }
 
 #[query]
 fn transform_openai_chat_completion(args: TransformArgs) -> HttpResponse {
+    transform_chat_completion(args, parse_openai_response)
+}
+
+#[query]
+fn transform_anthropic_messages(args: TransformArgs) -> HttpResponse {
+    transform_chat_completion(args, parse_anthropic_response)
+}
+
+// Turns the response of a provider into a ChatCompletion, with the parser of the provider
+fn transform_chat_completion(
+    args: TransformArgs,
+    parse_response: fn(&str) -> Result<ChatCompletion, String>,
+) -> HttpResponse {
     let mut res = HttpResponse {
         status: args.response.status.clone(),
         ..Default::default()
@@ -157,9 +294,8 @@ fn transform_openai_chat_completion(args: TransformArgs) -> HttpResponse {
             .expect("Transformed response is not UTF-8 encoded.");
         let json_str = res_str.replace("\n", "");
 
-        let openai_result: Result<OpenAIResult, serde_json::Error> =
-            serde_json::from_str(json_str.as_str());
-        if openai_result.is_err() {
+        let completion: Result<ChatCompletion, String> = parse_response(json_str.as_str());
+        if completion.is_err() {
             // log invalid json str
             ic_cdk::println!("Invalid JSON str = {:?}", json_str);
 
@@ -169,15 +305,7 @@ fn transform_openai_chat_completion(args: TransformArgs) -> HttpResponse {
             return res;
         }
 
-        let openai_body: OpenAIResult = openai_result.unwrap();
-        let msg_content = &openai_body.choices[0].message.content;
-
-        // replace ``` with empty string
-        let msg_content = msg_content.replace("```", "");
-        // replace extra json title with empty string
-        let msg_content = msg_content.replace("json", "");
-
-        res.body = msg_content.as_bytes().to_vec();
+        res.body = serde_json::to_vec(&completion.unwrap()).unwrap();
         return res;
     }
 
@@ -188,12 +316,27 @@ fn transform_openai_chat_completion(args: TransformArgs) -> HttpResponse {
 // entry function for user to ask questions
 #[update(guard = "assert_owner")]
 #[candid_method(update)]
-#[async_recursion]
 pub async fn generate_embeddings(
     content: String,
     num_retries: i8,
     opt_request_id: Option<String>,
 ) -> Result<Embeddings, String> {
+    generate_embeddings_with_usage(content, num_retries, opt_request_id)
+        .await
+        .map(|result| result.embeddings)
+}
+
+// same as generate_embeddings, also returning the tokens, HTTP outcalls and cycles spent
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+#[async_recursion]
+pub async fn generate_embeddings_with_usage(
+    content: String,
+    num_retries: i8,
+    opt_request_id: Option<String>,
+) -> Result<EmbeddingsResult, String> {
+    let start_cycles_balance: u64 = ic_cdk::api::canister_balance();
+
     let request_body = json!({
         "input": content,
         "model": OPENAI_EMBEDDINGS_MODEL,
@@ -240,7 +383,15 @@ pub async fn generate_embeddings(
 
             let openai_body: OpenAIEmbeddingResult = openai_result.unwrap();
             let embedding = &openai_body.data[0].embedding;
-            return Ok(embedding.clone());
+            return Ok(EmbeddingsResult {
+                embeddings: embedding.clone(),
+                usage: Usage {
+                    prompt_tokens: openai_body.usage.prompt_tokens as u64,
+                    http_outcalls: 1,
+                    cycles_used: get_cycles_used_since(start_cycles_balance),
+                    ..Default::default()
+                },
+            });
         }
         Err((r, m)) => {
             let message = format!(
@@ -250,8 +401,17 @@ pub async fn generate_embeddings(
 
             if num_retries < MAX_NUM_RETIRES {
                 ic_cdk::println!("Retrying generate_embeddings, num_retries: {}", num_retries);
-                return generate_embeddings(content.clone(), num_retries + 1, Some(request_id))
-                    .await;
+                let mut result = generate_embeddings_with_usage(
+                    content.clone(),
+                    num_retries + 1,
+                    Some(request_id),
+                )
+                .await;
+                if let Ok(result) = result.as_mut() {
+                    result.usage.http_outcalls += 1;
+                    result.usage.cycles_used = get_cycles_used_since(start_cycles_balance);
+                }
+                return result;
             }
 
             return Err(message);
@@ -281,6 +441,12 @@ fn transform_openai_embeddings(args: TransformArgs) -> HttpResponse {
 }
 // ---------------------- Supporting Functions ----------------------
 
+// Approximate cycles spent since the balance was read, ignoring top-ups received in between.
+// Requests handled while this one awaits its HTTP outcalls are counted too.
+fn get_cycles_used_since(start_cycles_balance: u64) -> u64 {
+    start_cycles_balance.saturating_sub(ic_cdk::api::canister_balance())
+}
+
 // Controller canister must be created with principal
 #[init]
 #[candid_method(init)]
@@ -299,6 +465,7 @@ fn init(
             gpt_model: gpt_model,
             battery_api_key: battery_api_key,
             battery_canister: battery_canister,
+            llm_providers: Vec::new(),
         };
     });
 
@@ -333,6 +500,52 @@ pub fn get_gpt_model() -> String {
     STATE.with(|state| (*state.borrow()).gpt_model.clone())
 }
 
+// Serves the models starting with one of the model prefixes of the provider,
+// replacing the provider of the same name
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+pub fn set_llm_provider(provider: LlmProvider) -> Result<(), String> {
+    validate_provider(&provider)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state
+            .llm_providers
+            .retain(|existing| existing.name != provider.name);
+        state.llm_providers.push(provider);
+    });
+
+    return Ok(());
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+pub fn remove_llm_provider(name: String) {
+    STATE.with(|state| {
+        state
+            .borrow_mut()
+            .llm_providers
+            .retain(|provider| provider.name != name)
+    });
+}
+
+// API keys are not returned
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+pub fn get_llm_providers() -> Vec<LlmProvider> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .llm_providers
+            .iter()
+            .map(|provider| LlmProvider {
+                api_key: String::new(),
+                ..provider.clone()
+            })
+            .collect()
+    })
+}
+
 #[update]
 fn start_cycles_check_timer(secs: u64) {
     let secs = Duration::from_secs(secs);
@@ -448,7 +661,9 @@ fn post_upgrade(
 // ---------------------- Candid declarations did file generator ----------------------
 #[cfg(test)]
 mod tests {
-    use crate::datatype::Embeddings;
+    use crate::datatype::{
+        AskResult, CommandSchema, Embeddings, EmbeddingsResult, LlmProvider, ThinkResult,
+    };
     use candid::{export_service, Principal};
 
     #[test]
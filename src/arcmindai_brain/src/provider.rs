@@ -0,0 +1,273 @@
+use ic_cdk::api::management_canister::http_request::HttpHeader;
+use serde_json::{json, Value};
+
+use crate::datatype::{
+    AnthropicResult, ChatCompletion, CommandSchema, LlmProvider, OpenAIResult, ProviderKind,
+    ToolCall,
+};
+use crate::httputil::{create_header, OPENAI_HOST};
+use crate::toolcall::create_tool_parameters;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+// Anthropic requires a limit on the completion tokens
+const ANTHROPIC_MAX_TOKENS: u32 = 4096;
+const ANTHROPIC_MAX_TEMPERATURE: f32 = 1.0;
+// These APIs don't dedupe by idempotency-key, so the replicas would not reach consensus
+const DIRECT_PROVIDER_HOSTS: [&str; 2] = ["api.openai.com", "api.anthropic.com"];
+
+// The OpenAI proxy serves the models without a provider
+pub fn create_default_provider(openai_api_key: String) -> LlmProvider {
+    LlmProvider {
+        name: "openai".to_string(),
+        kind: ProviderKind::OpenAI,
+        url: "https://".to_string() + OPENAI_HOST,
+        api_key: openai_api_key,
+        model_prefixes: Vec::new(),
+    }
+}
+
+// The first provider with a model prefix of the model name
+pub fn find_provider<'a>(providers: &'a [LlmProvider], model: &str) -> Option<&'a LlmProvider> {
+    providers.iter().find(|provider| {
+        provider
+            .model_prefixes
+            .iter()
+            .any(|prefix| model.starts_with(prefix.as_str()))
+    })
+}
+
+// HTTPS outcalls only reach https urls, through a proxy that dedupes by idempotency-key
+pub fn validate_provider(provider: &LlmProvider) -> Result<(), String> {
+    if provider.name.trim().is_empty() {
+        return Err("Provider name is empty.".to_string());
+    }
+    if !provider.url.starts_with("https://") || get_url_host(&provider.url).is_empty() {
+        return Err("Provider url must be a https url.".to_string());
+    }
+    if DIRECT_PROVIDER_HOSTS.contains(&get_url_host(&provider.url)) {
+        return Err(
+            "Provider url must be an idempotent proxy, not the provider API itself.".to_string(),
+        );
+    }
+    if provider.model_prefixes.is_empty()
+        || provider
+            .model_prefixes
+            .iter()
+            .any(|prefix| prefix.trim().is_empty())
+    {
+        return Err("Provider must have non-empty model prefixes.".to_string());
+    }
+
+    Ok(())
+}
+
+pub fn get_url_host(url: &str) -> &str {
+    let without_scheme = url.trim_start_matches("https://");
+    without_scheme.split('/').next().unwrap_or(without_scheme)
+}
+
+pub fn create_provider_header(provider: &LlmProvider, request_id: String) -> Vec<HttpHeader> {
+    let headers = create_header(
+        provider.api_key.clone(),
+        get_url_host(&provider.url).to_string(),
+        request_id,
+    );
+
+    match provider.kind {
+        ProviderKind::OpenAI => headers,
+        ProviderKind::Anthropic => {
+            let mut headers: Vec<HttpHeader> = headers
+                .into_iter()
+                .filter(|header| header.name != "authorization")
+                .collect();
+            headers.push(HttpHeader {
+                name: "x-api-key".to_string(),
+                value: provider.api_key.clone(),
+            });
+            headers.push(HttpHeader {
+                name: "anthropic-version".to_string(),
+                value: ANTHROPIC_VERSION.to_string(),
+            });
+            headers
+        }
+    }
+}
+
+fn create_tool(kind: ProviderKind, command: &CommandSchema) -> Result<Value, String> {
+    let parameters: Value = create_tool_parameters(command)?;
+
+    Ok(match kind {
+        ProviderKind::OpenAI => json!({
+            "type": "function",
+            "function": {
+                "name": command.name,
+                "description": command.description,
+                "parameters": parameters
+            }
+        }),
+        ProviderKind::Anthropic => json!({
+            "name": command.name,
+            "description": command.description,
+            "input_schema": parameters
+        }),
+    })
+}
+
+// Request body of a question, where the model must call exactly one of the commands if any
+pub fn create_request_body(
+    kind: ProviderKind,
+    model: &str,
+    question: &str,
+    temperature: f32,
+    commands: Option<&[CommandSchema]>,
+) -> Result<Value, String> {
+    let messages = json!([
+        {
+            "role": "user",
+            "content": question
+        }
+    ]);
+    let mut request_body = match kind {
+        ProviderKind::OpenAI => json!({
+            "model": model,
+            "messages": messages,
+            "temperature": temperature
+        }),
+        ProviderKind::Anthropic => json!({
+            "model": model,
+            "max_tokens": ANTHROPIC_MAX_TOKENS,
+            "messages": messages,
+            "temperature": temperature.min(ANTHROPIC_MAX_TEMPERATURE)
+        }),
+    };
+
+    if let Some(commands) = commands {
+        let tools: Vec<Value> = commands
+            .iter()
+            .map(|command| create_tool(kind, command))
+            .collect::<Result<Vec<Value>, String>>()?;
+        request_body["tools"] = json!(tools);
+        match kind {
+            ProviderKind::OpenAI => {
+                request_body["tool_choice"] = json!("required");
+                request_body["parallel_tool_calls"] = json!(false);
+            }
+            ProviderKind::Anthropic => {
+                request_body["tool_choice"] = json!({
+                    "type": "any",
+                    "disable_parallel_tool_use": true
+                });
+            }
+        }
+    }
+
+    Ok(request_body)
+}
+
+// The content is kept as it is, commands are read from the tool call
+pub fn parse_openai_response(body: &str) -> Result<ChatCompletion, String> {
+    let openai_body: OpenAIResult = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    let message = &openai_body
+        .choices
+        .first()
+        .ok_or("No choices in response.".to_string())?
+        .message;
+    let tool_call: Option<ToolCall> = message
+        .tool_calls
+        .as_ref()
+        .and_then(|tool_calls| tool_calls.first())
+        .map(|tool_call| ToolCall {
+            name: tool_call.function.name.clone(),
+            arguments: tool_call.function.arguments.clone(),
+        });
+
+    Ok(ChatCompletion {
+        content: message.content.clone().unwrap_or_default(),
+        tool_call,
+        prompt_tokens: openai_body.usage.prompt_tokens as u64,
+        completion_tokens: openai_body.usage.completion_tokens as u64,
+    })
+}
+
+// Text blocks make up the content, the first tool_use block is the tool call
+pub fn parse_anthropic_response(body: &str) -> Result<ChatCompletion, String> {
+    let anthropic_body: AnthropicResult = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    let content: String = anthropic_body
+        .content
+        .iter()
+        .filter(|block| block.block_type == "text")
+        .filter_map(|block| block.text.clone())
+        .collect();
+    let tool_call: Option<ToolCall> = anthropic_body
+        .content
+        .iter()
+        .find(|block| block.block_type == "tool_use")
+        .map(|block| ToolCall {
+            name: block.name.clone().unwrap_or_default(),
+            arguments: block.input.clone().unwrap_or(json!({})).to_string(),
+        });
+
+    Ok(ChatCompletion {
+        content,
+        tool_call,
+        prompt_tokens: anthropic_body.usage.input_tokens as u64,
+        completion_tokens: anthropic_body.usage.output_tokens as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_request_body, find_provider, parse_anthropic_response, validate_provider};
+    use crate::datatype::{CommandSchema, LlmProvider, ProviderKind};
+
+    #[test]
+    fn selects_provider_and_parses_anthropic_messages() {
+        let providers = vec![LlmProvider {
+            name: "anthropic".to_string(),
+            kind: ProviderKind::Anthropic,
+            url: "https://anthropic-proxy.example.com/v1/messages".to_string(),
+            api_key: "key".to_string(),
+            model_prefixes: vec!["claude-".to_string()],
+        }];
+        assert!(validate_provider(&providers[0]).is_ok());
+        let direct_provider = LlmProvider {
+            url: "https://api.anthropic.com/v1/messages".to_string(),
+            ..providers[0].clone()
+        };
+        assert!(validate_provider(&direct_provider).is_err());
+        assert_eq!(
+            find_provider(&providers, "claude-sonnet-4-5").map(|p| p.kind),
+            Some(ProviderKind::Anthropic)
+        );
+        assert!(find_provider(&providers, "gpt-4o").is_none());
+
+        let commands = vec![CommandSchema {
+            name: "google".to_string(),
+            description: "Google Search".to_string(),
+            parameters: r#"{"type":"object","properties":{"query":{"type":"string"}}}"#.to_string(),
+        }];
+        let request_body = create_request_body(
+            ProviderKind::Anthropic,
+            "claude-sonnet-4-5",
+            "question",
+            1.5,
+            Some(&commands),
+        )
+        .unwrap();
+        assert_eq!(request_body["temperature"], 1.0);
+        assert_eq!(request_body["tools"][0]["name"], "google");
+        assert_eq!(request_body["tool_choice"]["type"], "any");
+
+        let body = r#"{"id":"msg_1","type":"message","role":"assistant","model":"claude-sonnet-4-5",
+            "content":[{"type":"text","text":"Searching."},
+                {"type":"tool_use","id":"toolu_1","name":"google","input":{"args":{"query":"icp"}}}],
+            "stop_reason":"tool_use","usage":{"input_tokens":120,"output_tokens":30}}"#;
+        let completion = parse_anthropic_response(body).unwrap();
+        assert_eq!(completion.content, "Searching.");
+        let tool_call = completion.tool_call.unwrap();
+        assert_eq!(tool_call.name, "google");
+        assert_eq!(tool_call.arguments, r#"{"args":{"query":"icp"}}"#);
+        assert_eq!(completion.prompt_tokens, 120);
+        assert_eq!(completion.completion_tokens, 30);
+    }
+}
@@ -0,0 +1,93 @@
+use serde_json::{json, Value};
+
+use crate::datatype::{AgentResponse, Command, CommandSchema, Thoughts, ToolCall};
+
+// Every command is called with the thoughts behind it, next to its own args
+fn create_thoughts_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "text": { "type": "string", "description": "thought" },
+            "reasoning": { "type": "string", "description": "reasoning" },
+            "plan": { "type": "string", "description": "short bulleted list that conveys long-term plan" },
+            "criticism": { "type": "string", "description": "constructive self-criticism" },
+            "speak": { "type": "string", "description": "thoughts summary to say to user" }
+        },
+        "required": ["text", "reasoning", "plan", "criticism", "speak"]
+    })
+}
+
+// JSON schema of the tool of a command, taking {"thoughts": .., "args": ..}
+pub fn create_tool_parameters(command: &CommandSchema) -> Result<Value, String> {
+    let args_schema: Value = serde_json::from_str(&command.parameters)
+        .map_err(|e| format!("Invalid parameters of command {}: {}", command.name, e))?;
+
+    Ok(json!({
+        "type": "object",
+        "properties": {
+            "thoughts": create_thoughts_schema(),
+            "args": args_schema
+        },
+        "required": ["thoughts", "args"]
+    }))
+}
+
+pub fn parse_tool_call(tool_call: &ToolCall) -> Result<AgentResponse, String> {
+    let arguments: Value = serde_json::from_str(&tool_call.arguments).map_err(|e| {
+        format!(
+            "Invalid arguments of command {}: {}, {}",
+            tool_call.name, tool_call.arguments, e
+        )
+    })?;
+
+    let thoughts: Thoughts =
+        serde_json::from_value(arguments["thoughts"].clone()).unwrap_or_default();
+    let args: Value = match &arguments["args"] {
+        Value::Object(args) => Value::Object(args.clone()),
+        _ => json!({}),
+    };
+
+    Ok(AgentResponse {
+        thoughts,
+        command: Command {
+            name: tool_call.name.clone(),
+            args: args.to_string(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_tool_parameters, parse_tool_call};
+    use crate::datatype::{CommandSchema, ToolCall};
+
+    #[test]
+    fn creates_and_parses_tool_calls() {
+        let command = CommandSchema {
+            name: "google".to_string(),
+            description: "Google Search".to_string(),
+            parameters: r#"{"type":"object","properties":{"query":{"type":"string"}}}"#.to_string(),
+        };
+        let parameters = create_tool_parameters(&command).unwrap();
+        assert_eq!(
+            parameters["properties"]["args"]["properties"]["query"]["type"],
+            "string"
+        );
+
+        let tool_call = ToolCall {
+            name: "google".to_string(),
+            arguments: r#"{"thoughts":{"speak":"Searching json```"},"args":{"query":"icp json"}}"#
+                .to_string(),
+        };
+        let response = parse_tool_call(&tool_call).unwrap();
+        assert_eq!(response.thoughts.speak, "Searching json```");
+        assert_eq!(response.thoughts.plan, "");
+        assert_eq!(response.command.args, r#"{"query":"icp json"}"#);
+
+        let tool_call = ToolCall {
+            name: "google".to_string(),
+            arguments: "{\"args\":".to_string(),
+        };
+        assert!(parse_tool_call(&tool_call).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::cost::add_cost;
+use crate::datatype::{ChatHistory, ChatRole, Cost, CostEstimate, CostPrices, GoalBudget, Subtask};
+use crate::planner::format_plan;
+
+const TOKENS_PER_MILLION: f64 = 1_000_000.0;
+const CYCLES_PER_TRILLION: f64 = 1_000_000_000_000.0;
+
+pub fn get_total_tokens(cost: &Cost) -> u64 {
+    cost.prompt_tokens + cost.completion_tokens + cost.embedding_tokens
+}
+
+pub fn get_total_cycles(cost: &Cost) -> u64 {
+    cost.controller_cycles + cost.brain_cycles + cost.tools_cycles
+}
+
+pub fn convert_cost_to_usd(cost: &Cost, prices: &CostPrices) -> f64 {
+    cost.prompt_tokens as f64 / TOKENS_PER_MILLION * prices.usd_per_million_prompt_tokens
+        + cost.completion_tokens as f64 / TOKENS_PER_MILLION
+            * prices.usd_per_million_completion_tokens
+        + cost.embedding_tokens as f64 / TOKENS_PER_MILLION
+            * prices.usd_per_million_embedding_tokens
+        + get_total_cycles(cost) as f64 / CYCLES_PER_TRILLION * prices.usd_per_trillion_cycles
+}
+
+// Describes the first limit of the budget reached by the cost, if any
+pub fn find_exceeded_budget(
+    cost: &Cost,
+    budget: &GoalBudget,
+    prices: Option<&CostPrices>,
+) -> Option<String> {
+    if let Some(max_cycles) = budget.max_cycles {
+        let cycles = get_total_cycles(cost);
+        if cycles >= max_cycles {
+            return Some(format!("{} of {} cycles spent", cycles, max_cycles));
+        }
+    }
+    if let Some(max_tokens) = budget.max_tokens {
+        let tokens = get_total_tokens(cost);
+        if tokens >= max_tokens {
+            return Some(format!("{} of {} tokens spent", tokens, max_tokens));
+        }
+    }
+    if let (Some(max_usd), Some(prices)) = (budget.max_usd, prices) {
+        let usd = convert_cost_to_usd(cost, prices);
+        if usd >= max_usd {
+            return Some(format!("{:.4} of {:.4} USD spent", usd, max_usd));
+        }
+    }
+
+    None
+}
+
+// Result of a goal stopped early: why it stopped, its plan progress and its latest thoughts
+pub fn create_partial_result(
+    reason: &str,
+    plan: &Option<Vec<Subtask>>,
+    history: &[ChatHistory],
+) -> String {
+    let latest_thoughts: Option<String> = history
+        .iter()
+        .rev()
+        .filter(|chat| chat.role == ChatRole::ArcMind)
+        .find_map(|chat| {
+            let cof_json = serde_json::from_str::<serde_json::Value>(&chat.content).ok()?;
+            cof_json["thoughts"]["speak"]
+                .as_str()
+                .or(cof_json["thoughts"]["text"].as_str())
+                .map(|thoughts| thoughts.to_string())
+        });
+
+    let mut partial_result = format!(
+        "{}\n\nPartial result.\nPlan progress:\n{}",
+        reason,
+        format_plan(plan)
+    );
+    if let Some(latest_thoughts) = latest_thoughts {
+        partial_result.push_str(&format!("\nLatest thoughts:\n{}", latest_thoughts));
+    }
+
+    partial_result
+}
+
+/*
+ * Estimates the cost of a goal from past goals, given their cost and number of steps.
+ * The cost per step is averaged over all steps, and multiplied by the average number of steps,
+ * capped at the thought budget of a goal.
+ */
+pub fn estimate_goal_cost(
+    samples: &[(Cost, u64)],
+    max_num_steps: u64,
+    prices: Option<&CostPrices>,
+) -> Option<CostEstimate> {
+    let samples: Vec<&(Cost, u64)> = samples.iter().filter(|(_, steps)| *steps > 0).collect();
+    if samples.is_empty() {
+        return None;
+    }
+
+    let mut total = Cost::default();
+    let mut num_steps: u64 = 0;
+    for (cost, steps) in samples.iter() {
+        add_cost(&mut total, cost);
+        num_steps += steps;
+    }
+
+    let expected_num_steps: u64 = (num_steps / samples.len() as u64).clamp(1, max_num_steps);
+    let scale = |value: u64| value * expected_num_steps / num_steps;
+    let cost = Cost {
+        prompt_tokens: scale(total.prompt_tokens),
+        completion_tokens: scale(total.completion_tokens),
+        embedding_tokens: scale(total.embedding_tokens),
+        http_outcalls: scale(total.http_outcalls),
+        controller_cycles: scale(total.controller_cycles),
+        brain_cycles: scale(total.brain_cycles),
+        tools_cycles: scale(total.tools_cycles),
+    };
+
+    Some(CostEstimate {
+        num_goals_sampled: samples.len() as u64,
+        expected_num_steps,
+        usd: prices.map(|prices| convert_cost_to_usd(&cost, prices)),
+        cost,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_goal_cost, find_exceeded_budget};
+    use crate::datatype::{Cost, CostPrices, GoalBudget};
+
+    fn create_cost(prompt_tokens: u64, brain_cycles: u64) -> Cost {
+        Cost {
+            prompt_tokens,
+            brain_cycles,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn finds_exceeded_budget() {
+        let prices = CostPrices {
+            usd_per_million_prompt_tokens: 10.0,
+            usd_per_million_completion_tokens: 30.0,
+            usd_per_million_embedding_tokens: 0.1,
+            usd_per_trillion_cycles: 1.3,
+        };
+        let budget = GoalBudget {
+            max_cycles: None,
+            max_tokens: Some(50_000),
+            max_usd: Some(0.1),
+        };
+
+        let cost = create_cost(9_000, 0);
+        assert_eq!(find_exceeded_budget(&cost, &budget, Some(&prices)), None);
+
+        let cost = create_cost(10_000, 0);
+        assert_eq!(
+            find_exceeded_budget(&cost, &budget, Some(&prices)),
+            Some("0.1000 of 0.1000 USD spent".to_string())
+        );
+        assert_eq!(find_exceeded_budget(&cost, &budget, None), None);
+
+        let budget = GoalBudget {
+            max_cycles: Some(1_000_000),
+            max_tokens: None,
+            max_usd: None,
+        };
+        let cost = create_cost(0, 1_000_000);
+        assert_eq!(
+            find_exceeded_budget(&cost, &budget, None),
+            Some("1000000 of 1000000 cycles spent".to_string())
+        );
+    }
+
+    #[test]
+    fn estimates_cost_from_past_goals() {
+        let samples = vec![(create_cost(1000, 400), 10), (create_cost(3000, 800), 30)];
+
+        let estimate = estimate_goal_cost(&samples, 100, None).unwrap();
+
+        assert_eq!(estimate.num_goals_sampled, 2);
+        assert_eq!(estimate.expected_num_steps, 20);
+        assert_eq!(estimate.cost, create_cost(2000, 600));
+        assert_eq!(estimate.usd, None);
+        assert!(estimate_goal_cost(&[], 100, None).is_none());
+    }
+}
@@ -0,0 +1,154 @@
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+// text-embedding-ada-002 accepts up to 8191 tokens, smaller chunks give sharper vectors
+pub const CHUNK_MAX_TOKENS: usize = 500;
+pub const CHUNK_OVERLAP_TOKENS: usize = 50;
+
+// building the BPE ranks is expensive, so it is done once per canister instance
+thread_local! {
+    static BPE: CoreBPE = cl100k_base().unwrap();
+}
+
+pub struct Chunk {
+    pub content: String,
+    pub index: u32,
+}
+
+/*
+ * Splits content into chunks of at most max_tokens tokens on word boundaries.
+ * Consecutive chunks share about overlap_tokens tokens so that sentences cut at
+ * a boundary still appear whole in one of the chunks.
+ */
+pub fn chunk_content(content: &str, max_tokens: usize, overlap_tokens: usize) -> Vec<Chunk> {
+    // count tokens of each word, keeping its trailing whitespace
+    let words: Vec<(&str, usize)> = BPE.with(|bpe| {
+        content
+            .split_inclusive(char::is_whitespace)
+            .map(|word| (word, bpe.encode_ordinary(word).len()))
+            .collect()
+    });
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut start = 0;
+
+    while start < words.len() {
+        // take as many words as fit, at least one to always make progress
+        let mut end = start;
+        let mut num_tokens = 0;
+        while end < words.len() && (end == start || num_tokens + words[end].1 <= max_tokens) {
+            num_tokens += words[end].1;
+            end += 1;
+        }
+
+        let chunk_text: String = words[start..end].iter().map(|(word, _)| *word).collect();
+        chunks.push(Chunk {
+            content: chunk_text.trim().to_string(),
+            index: chunks.len() as u32,
+        });
+
+        if end >= words.len() {
+            break;
+        }
+
+        // step back over the overlap words for the next chunk
+        let mut next_start = end;
+        let mut overlap = 0;
+        while next_start > start + 1 && overlap + words[next_start - 1].1 <= overlap_tokens {
+            overlap += words[next_start - 1].1;
+            next_start -= 1;
+        }
+        start = next_start;
+    }
+
+    chunks
+}
+
+// Length of the longest run of whole words ending previous that next starts with
+fn find_overlap_len(previous: &str, next: &str) -> usize {
+    next.char_indices()
+        .filter(|(_, c)| c.is_whitespace())
+        .map(|(i, _)| i)
+        .chain(std::iter::once(next.len()))
+        .rfind(|&i| {
+            let prefix = &next[..i];
+            previous.ends_with(prefix)
+                && previous[..previous.len() - prefix.len()]
+                    .chars()
+                    .next_back()
+                    .is_none_or(char::is_whitespace)
+        })
+        .unwrap_or(0)
+}
+
+// Joins consecutive chunks of a document, dropping the overlap each chunk shares with the previous
+pub fn join_chunks(chunks: &[String]) -> String {
+    let mut joined = String::new();
+
+    for chunk in chunks {
+        if joined.is_empty() {
+            joined.push_str(chunk);
+            continue;
+        }
+
+        let overlap_len = find_overlap_len(&joined, chunk);
+        let rest = chunk[overlap_len..].trim_start();
+        if rest.is_empty() {
+            continue;
+        }
+        // chunks without overlap are not contiguous in the document
+        joined.push(if overlap_len > 0 { ' ' } else { '\n' });
+        joined.push_str(rest);
+    }
+
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunk_content, join_chunks};
+
+    #[test]
+    fn chunks_with_overlap() {
+        let content = (0..300)
+            .map(|i| format!("word{}", i))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let chunks = chunk_content(&content, 100, 20);
+
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks[0].index, 0);
+        assert!(chunks[0].content.starts_with("word0 "));
+        assert!(chunks.last().unwrap().content.ends_with("word299"));
+
+        // the next chunk starts inside the previous one
+        let first_word_of_second = chunks[1].content.split(' ').next().unwrap();
+        assert!(chunks[0].content.contains(first_word_of_second));
+    }
+
+    #[test]
+    fn short_content_is_single_chunk() {
+        let chunks = chunk_content("ArcMind AI", 100, 20);
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].content, "ArcMind AI");
+    }
+
+    #[test]
+    fn joins_chunks_without_overlap() {
+        let content = (0..300)
+            .map(|i| format!("word{}", i))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let chunks: Vec<String> = chunk_content(&content, 100, 20)
+            .into_iter()
+            .map(|chunk| chunk.content)
+            .collect();
+
+        assert_eq!(join_chunks(&chunks), content);
+        assert_eq!(
+            join_chunks(&["a b".to_string(), "c d".to_string()]),
+            "a b\nc d"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use serde_json::{json, Value};
+
+use crate::json_repair::validate_command_args;
+
+use crate::datatype::{
+    AgentResponse, CommandSchema, PROMPT_CMD_ASK_USER, PROMPT_CMD_BEAMFI_STREAM_PAYMENT,
+    PROMPT_CMD_BROWSE_WEBSITE, PROMPT_CMD_GOOGLE, PROMPT_CMD_GRAPH_QUERY, PROMPT_CMD_SET_PLAN,
+    PROMPT_CMD_SHUTDOWN, PROMPT_CMD_START_AGENT, PROMPT_CMD_UPDATE_SUBTASK,
+    PROMPT_CMD_WAIT_FOR_AGENTS, PROMPT_CMD_WRITE_FILE_AND_SHUTDOWN,
+};
+
+fn create_command_schema(name: &str, description: &str, properties: Value) -> CommandSchema {
+    let required: Vec<String> = properties
+        .as_object()
+        .map(|properties| properties.keys().cloned().collect())
+        .unwrap_or_default();
+
+    CommandSchema {
+        name: name.to_string(),
+        description: description.to_string(),
+        parameters: json!({
+            "type": "object",
+            "properties": properties,
+            "required": required
+        })
+        .to_string(),
+    }
+}
+
+fn string_schema(description: &str) -> Value {
+    json!({ "type": "string", "description": description })
+}
+
+fn string_array_schema(description: &str) -> Value {
+    json!({ "type": "array", "items": { "type": "string" }, "description": description })
+}
+
+// Commands listed in COF_PROMPT, given to the brain as functions to call
+pub fn create_command_schemas(is_ask_user_enabled: bool) -> Vec<CommandSchema> {
+    let mut commands: Vec<CommandSchema> = vec![
+        create_command_schema(
+            PROMPT_CMD_START_AGENT,
+            "Start GPT Agent to delegate a subtask",
+            json!({
+                "name": string_schema("name of the agent"),
+                "task": string_schema("short task description"),
+                "prompt": string_schema("prompt of the agent")
+            }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_GOOGLE,
+            "Google Search",
+            json!({ "query": string_schema("search query") }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_BROWSE_WEBSITE,
+            "Browse Website",
+            json!({
+                "url": string_schema("url of the website"),
+                "question": string_schema("what you want to find on the website")
+            }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_WRITE_FILE_AND_SHUTDOWN,
+            "Write to file and shutdown",
+            json!({
+                "key": string_schema("file key"),
+                "text": string_schema("file text")
+            }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_SHUTDOWN,
+            "Task Complete (Shutdown)",
+            json!({ "reason": string_schema("reason, with the result of the goal") }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_BEAMFI_STREAM_PAYMENT,
+            "Stream Payment to recipient with BeamFi",
+            json!({
+                "amount": string_schema("amount"),
+                "token_type": string_schema("token type"),
+                "recipient_principal_id": string_schema("principal id of the recipient")
+            }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_GRAPH_QUERY,
+            "Query Knowledge Graph of what you learned about an entity",
+            json!({ "entity": string_schema("entity name") }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_SET_PLAN,
+            "Set Plan of subtasks for the goal",
+            json!({ "subtasks": string_array_schema("subtasks in order") }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_UPDATE_SUBTASK,
+            "Update Subtask status in the plan",
+            json!({
+                "index": string_schema("subtask number"),
+                "status": {
+                    "type": "string",
+                    "enum": ["pending", "in_progress", "done", "skipped"]
+                }
+            }),
+        ),
+        create_command_schema(
+            PROMPT_CMD_WAIT_FOR_AGENTS,
+            "Wait for started GPT Agents to finish and report their results",
+            json!({ "agent_ids": string_array_schema("ids of the started agents") }),
+        ),
+    ];
+
+    if is_ask_user_enabled {
+        commands.push(create_command_schema(
+            PROMPT_CMD_ASK_USER,
+            "Ask the user a question and wait for the answer",
+            json!({ "question": string_schema("question") }),
+        ));
+    }
+
+    commands
+}
+
+// Chain of thoughts command called by the brain, in the RESPONSE_FORMAT
+pub fn create_agent_command(response: &AgentResponse) -> Value {
+    let args: Value = serde_json::from_str(&response.command.args).unwrap_or(json!({}));
+
+    json!({
+        "thoughts": response.thoughts,
+        "command": {
+            "name": response.command.name,
+            "args": args
+        }
+    })
+}
+
+// The command called by the brain as text, for the chat history and the owner to review
+pub fn format_agent_response(response: &AgentResponse) -> String {
+    create_agent_command(response).to_string()
+}
+
+// Checks the args of the command called by the brain against the schema of the command
+pub fn validate_agent_response(
+    response: &AgentResponse,
+    commands: &[CommandSchema],
+) -> Result<(), String> {
+    let args: Value = serde_json::from_str(&response.command.args)
+        .map_err(|e| format!("Invalid args of command {}: {}", response.command.name, e))?;
+
+    validate_command_args(&response.command.name, &args, commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_command_schemas, format_agent_response, validate_agent_response};
+    use crate::datatype::{AgentResponse, Command, Thoughts, PROMPT_CMD_ASK_USER};
+
+    #[test]
+    fn creates_command_schemas_and_formats_responses() {
+        let commands = create_command_schemas(false);
+        assert!(!commands.iter().any(|c| c.name == PROMPT_CMD_ASK_USER));
+        assert!(create_command_schemas(true)
+            .iter()
+            .any(|c| c.name == PROMPT_CMD_ASK_USER));
+
+        let google = commands.iter().find(|c| c.name == "google").unwrap();
+        let parameters: serde_json::Value = serde_json::from_str(&google.parameters).unwrap();
+        assert_eq!(parameters["required"][0], "query");
+
+        let response = AgentResponse {
+            thoughts: Thoughts {
+                speak: "Searching for the json spec".to_string(),
+                ..Default::default()
+            },
+            command: Command {
+                name: "google".to_string(),
+                args: r#"{"query":"```json spec"}"#.to_string(),
+            },
+        };
+        let cof_json: serde_json::Value =
+            serde_json::from_str(&format_agent_response(&response)).unwrap();
+        assert_eq!(cof_json["thoughts"]["speak"], "Searching for the json spec");
+        assert_eq!(cof_json["command"]["name"], "google");
+        assert_eq!(cof_json["command"]["args"]["query"], "```json spec");
+        assert!(validate_agent_response(&response, &commands).is_ok());
+
+        let response = AgentResponse {
+            command: Command {
+                name: "google".to_string(),
+                args: "{}".to_string(),
+            },
+            ..response
+        };
+        assert_eq!(
+            validate_agent_response(&response, &commands),
+            Err("Missing args of command google: query.".to_string())
+        );
+    }
+}
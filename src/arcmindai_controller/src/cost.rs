@@ -0,0 +1,110 @@
+use crate::datatype::{Cost, PeriodCost, Timestamp, Usage};
+
+// daily periods are kept for a year
+pub const MAX_NUM_COST_PERIODS: usize = 366;
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+pub enum CostSource {
+    Brain,
+    Embeddings,
+    Tools,
+}
+
+pub fn create_usage_cost(source: CostSource, usage: &Usage) -> Cost {
+    match source {
+        CostSource::Brain => Cost {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            http_outcalls: usage.http_outcalls,
+            brain_cycles: usage.cycles_used,
+            ..Default::default()
+        },
+        CostSource::Embeddings => Cost {
+            embedding_tokens: usage.prompt_tokens,
+            http_outcalls: usage.http_outcalls,
+            brain_cycles: usage.cycles_used,
+            ..Default::default()
+        },
+        CostSource::Tools => Cost {
+            http_outcalls: usage.http_outcalls,
+            tools_cycles: usage.cycles_used,
+            ..Default::default()
+        },
+    }
+}
+
+pub fn add_cost(total: &mut Cost, cost: &Cost) {
+    total.prompt_tokens += cost.prompt_tokens;
+    total.completion_tokens += cost.completion_tokens;
+    total.embedding_tokens += cost.embedding_tokens;
+    total.http_outcalls += cost.http_outcalls;
+    total.controller_cycles += cost.controller_cycles;
+    total.brain_cycles += cost.brain_cycles;
+    total.tools_cycles += cost.tools_cycles;
+}
+
+pub fn get_period_start(now: Timestamp) -> Timestamp {
+    now - now % NANOS_PER_DAY
+}
+
+// Adds the cost to the period of now, dropping the oldest periods beyond MAX_NUM_COST_PERIODS
+pub fn add_period_cost(periods: &mut Vec<PeriodCost>, now: Timestamp, cost: &Cost) {
+    let period_start: Timestamp = get_period_start(now);
+
+    match periods
+        .iter_mut()
+        .find(|period| period.period_start == period_start)
+    {
+        Some(period) => add_cost(&mut period.cost, cost),
+        None => {
+            periods.push(PeriodCost {
+                period_start,
+                cost: cost.clone(),
+            });
+            if periods.len() > MAX_NUM_COST_PERIODS {
+                periods.remove(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_period_cost, create_usage_cost, CostSource, NANOS_PER_DAY};
+    use crate::datatype::{Cost, PeriodCost, Usage};
+
+    #[test]
+    fn adds_usage_to_daily_periods() {
+        let usage = Usage {
+            prompt_tokens: 100,
+            completion_tokens: 20,
+            http_outcalls: 1,
+            cycles_used: 5000,
+        };
+        let ask_cost = create_usage_cost(CostSource::Brain, &usage);
+        let embeddings_cost = create_usage_cost(CostSource::Embeddings, &usage);
+        assert_eq!(ask_cost.completion_tokens, 20);
+        assert_eq!(embeddings_cost.embedding_tokens, 100);
+        assert_eq!(embeddings_cost.prompt_tokens, 0);
+
+        let mut periods: Vec<PeriodCost> = Vec::new();
+        add_period_cost(&mut periods, NANOS_PER_DAY + 10, &ask_cost);
+        add_period_cost(&mut periods, 2 * NANOS_PER_DAY - 1, &embeddings_cost);
+        add_period_cost(&mut periods, 2 * NANOS_PER_DAY, &ask_cost);
+
+        assert_eq!(periods.len(), 2);
+        assert_eq!(periods[0].period_start, NANOS_PER_DAY);
+        assert_eq!(
+            periods[0].cost,
+            Cost {
+                prompt_tokens: 100,
+                completion_tokens: 20,
+                embedding_tokens: 100,
+                http_outcalls: 2,
+                brain_cycles: 10000,
+                ..Default::default()
+            }
+        );
+        assert_eq!(periods[1].period_start, 2 * NANOS_PER_DAY);
+    }
+}
@@ -6,8 +6,22 @@ use std::borrow::Cow;
 use ic_stable_structures::{BoundedStorable, Storable};
 
 const MAX_VALUE_SIZE: u32 = 1024 * 1024;
+// fits embeddings of up to 3072 dimensions e.g text-embedding-3-large
+const MAX_EMBEDDINGS_VALUE_SIZE: u32 = 16 * 1024;
+const MAX_GRAPH_VALUE_SIZE: u32 = 2 * 1024;
+// longer texts are truncated so that the four texts of a relation fit MAX_GRAPH_VALUE_SIZE
+pub const MAX_GRAPH_TEXT_BYTES: usize = 400;
+const MAX_STEP_TRACE_VALUE_SIZE: u32 = 8 * 1024;
+// longer command args are truncated to fit MAX_STEP_TRACE_VALUE_SIZE
+pub const MAX_STEP_TRACE_ARGS_CHARS: usize = 1000;
 
 pub const VEC_SEARCH_TOP_K_NN: usize = 3;
+pub const VEC_SEARCH_TOP_K_LESSONS: usize = 2;
+// number of search results re-ranked by similarity, recency and importance
+pub const VEC_SEARCH_NUM_CANDIDATES: usize = 12;
+// hybrid search re-scores a wider pool of candidates, so keyword matches can come up
+pub const VEC_SEARCH_NUM_HYBRID_CANDIDATES: usize = 36;
+pub const MAX_MEMORY_PAGE_SIZE: u64 = 100;
 
 pub const PROMPT_CMD_GOOGLE: &str = "google";
 pub const PROMPT_CMD_BROWSE_WEBSITE: &str = "browse_website";
@@ -16,6 +30,14 @@ pub const PROMPT_CMD_WRITE_FILE_AND_SHUTDOWN: &str = "write_file_and_shutdown";
 pub const PROMPT_CMD_DO_NOTHING: &str = "do_nothing";
 pub const PROMPT_CMD_SHUTDOWN: &str = "shutdown";
 pub const PROMPT_CMD_BEAMFI_STREAM_PAYMENT: &str = "beamfi_stream_payment";
+pub const PROMPT_CMD_GRAPH_QUERY: &str = "graph_query";
+pub const PROMPT_CMD_SET_PLAN: &str = "set_plan";
+pub const PROMPT_CMD_UPDATE_SUBTASK: &str = "update_subtask";
+pub const PROMPT_CMD_WAIT_FOR_AGENTS: &str = "wait_for_agents";
+pub const PROMPT_CMD_ASK_USER: &str = "ask_user";
+
+pub const VEC_NAMESPACE_DOCUMENT: &str = "document";
+pub const VEC_NAMESPACE_LESSON: &str = "lesson";
 
 pub const TOP_CMD_AGENT_NAME: &str = "ArcMind";
 pub const TOP_CMD_AGENT_TASK: &str = "knowing the greatest knowledge of the world";
@@ -27,15 +49,253 @@ pub enum VecQuery {
     Embeddings(Vec<f32>),
 }
 
+// Combines a BM25 keyword score of the query text with the vector similarity score,
+// computed by the controller over the candidates of the vector search
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct HybridSearchWeights {
+    pub keyword_weight: f32,
+    pub vector_weight: f32,
+}
+
+// Weights of the memory retrieval ranking score
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct RetrievalWeights {
+    pub similarity: f32,
+    pub recency: f32,
+    pub importance: f32,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum FadeAction {
+    Archive,
+    Delete,
+}
+
+// Memories whose importance decayed by time since last access falls below threshold are faded
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct MemoryFadePolicy {
+    pub threshold: f32,
+    pub action: FadeAction,
+}
+
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct VecDoc {
     pub content: String,
     pub embeddings: Embeddings,
+    pub namespace: Option<String>,
+    // id of the document this chunk was split from, and its position in it
+    pub parent_id: Option<String>,
+    pub chunk_index: Option<u32>,
+    // 0.0 - 1.0, rated by the LLM when stored
+    pub importance: Option<f32>,
 }
 
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct PlainDoc {
+    pub id: Option<u64>,
+    pub content: String,
+    pub parent_id: Option<String>,
+    pub chunk_index: Option<u32>,
+    // similarity to the search query
+    pub score: Option<f32>,
+    pub importance: Option<f32>,
+    pub created_at: Option<Timestamp>,
+    pub last_accessed_at: Option<Timestamp>,
+}
+
+// Long term memory entry with metadata, as stored in the vector canister
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct MemoryDoc {
+    pub id: u64,
+    pub content: String,
+    pub namespace: Option<String>,
+    pub parent_id: Option<String>,
+    pub chunk_index: Option<u32>,
+    pub is_pinned: bool,
+    pub importance: Option<f32>,
+    pub access_count: Option<u64>,
+    pub last_accessed_at: Option<Timestamp>,
+    pub created_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct MemoryPage {
+    pub docs: Vec<MemoryDoc>,
+    pub total: u64,
+}
+
+// Knowledge base document uploaded by the owner to seed long term memory
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum DocumentFormat {
+    Markdown,
+    PlainText,
+    Json,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum DocumentStatus {
+    Uploading,
+    Ingesting,
+    Ingested,
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct KnowledgeDocument {
+    pub doc_id: String,
+    pub title: String,
+    pub format: DocumentFormat,
+    pub status: DocumentStatus,
+    pub num_chunks: u32,
+    pub num_chunks_ingested: u32,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
+}
+
+// Content parts received so far for a document that is still uploading
+#[derive(Serialize, Deserialize, Default)]
+pub struct DocumentUpload {
+    pub doc_id: String,
     pub content: String,
+    // chunks of the previous version of the document are replaced once it is ingested
+    #[serde(default)]
+    pub has_previous_version: bool,
+}
+
+// A goal template in a workflow. {{node_id}} placeholders in the template are
+// replaced by the results of the goals of the nodes it depends on.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct WorkflowNode {
+    pub node_id: String,
+    pub goal_template: String,
+    pub depends_on: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Workflow {
+    pub workflow_id: String,
+    pub name: String,
+    pub nodes: Vec<WorkflowNode>,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum WorkflowRunStatus {
+    Running,
+    Complete,
+    // a node goal stopped without completing, or did not complete in time
+    Failed(String),
+}
+
+// goal_key is None until all dependencies of the node are Complete
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct WorkflowRunNode {
+    pub node_id: String,
+    pub goal_key: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct WorkflowRun {
+    pub run_id: u64,
+    pub workflow_id: String,
+    pub status: WorkflowRunStatus,
+    pub nodes: Vec<WorkflowRunNode>,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct WorkflowNodeProgress {
+    pub node_id: String,
+    pub goal_key: Option<u64>,
+    pub status: Option<GoalStatus>,
+    pub result: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct WorkflowRunProgress {
+    pub run_id: u64,
+    pub workflow_id: String,
+    pub status: WorkflowRunStatus,
+    pub nodes: Vec<WorkflowNodeProgress>,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub enum ScheduleTrigger {
+    // every given number of seconds
+    Interval(u64),
+    // "minute hour day_of_month month day_of_week" in UTC e.g. "0 9 * * 1" for Mondays at 09:00
+    Cron(String),
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ScheduleRun {
+    pub goal_key: u64,
+    pub started_at: Timestamp,
+}
+
+// A goal created from goal_template every time the trigger fires.
+// {{date}} placeholders in the template are replaced by the date of the run.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct GoalSchedule {
+    pub schedule_id: u64,
+    pub name: String,
+    pub trigger: ScheduleTrigger,
+    pub goal_template: String,
+    pub max_num_thoughts: Option<u16>,
+    pub is_paused: bool,
+    pub next_run_at: Timestamp,
+    pub runs: Vec<ScheduleRun>,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum WatchSource {
+    WebPage,
+    // RSS or Atom feed, compared by its items
+    Feed,
+}
+
+// Polls url and starts a goal from goal_template when its content changes.
+// {{url}} and {{diff}} placeholders in the template are replaced by the url and the changes.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct Watcher {
+    pub watcher_id: u64,
+    pub name: String,
+    pub url: String,
+    pub source: WatchSource,
+    pub goal_template: String,
+    pub interval_secs: u64,
+    // changes of fewer lines or feed items are ignored
+    pub min_changed_lines: u32,
+    pub max_num_thoughts: Option<u16>,
+    pub is_paused: bool,
+    pub fingerprint: Option<String>,
+    pub next_check_at: Timestamp,
+    pub last_checked_at: Option<Timestamp>,
+    pub last_changed_at: Option<Timestamp>,
+    pub runs: Vec<ScheduleRun>,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
+}
+
+// Lines or feed items of the last content seen by a watcher, kept to diff the next content
+#[derive(Serialize, Deserialize, Default)]
+pub struct WatcherContent {
+    pub watcher_id: u64,
+    pub lines: Vec<String>,
+}
+
+// Recorded output returned for a google query or browsed url of a goal in dry run
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct DryRunFixture {
+    pub command: String,
+    pub input: String,
+    pub output: String,
 }
 
 #[derive(Serialize)]
@@ -44,20 +304,47 @@ pub struct PromptContext {
     pub agent_task: String,
     pub agent_goal: String,
     pub current_date_time: String,
-    pub response_format: String,
+    pub current_plan: String,
+    pub is_ask_user_enabled: bool,
+    pub guidance: String,
     pub past_events: String,
 }
 
+#[derive(Serialize)]
+pub struct MemoryImportancePromptContext {
+    pub memory_content: String,
+}
+
+#[derive(Serialize)]
+pub struct ReflectionPromptContext {
+    pub agent_goal: String,
+    pub transcript: String,
+}
+
+#[derive(Serialize)]
+pub struct GraphExtractionPromptContext {
+    pub source: String,
+    pub content: String,
+}
+
 #[derive(Serialize)]
 pub struct WebQueryPromptContext {
     pub web_query: String,
     pub web_page_content: String,
 }
 
-#[derive(CandidType, Deserialize, PartialEq)]
+#[derive(Serialize)]
+pub struct JsonRepairPromptContext {
+    pub error: String,
+    pub response_format: String,
+    pub malformed_json: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
 pub enum GoalStatus {
     Scheduled,
     Running,
+    Waiting,
     Complete,
 }
 
@@ -82,6 +369,7 @@ pub struct ChatHistory {
     pub content: String,
     pub role: ChatRole,
     pub created_at: Timestamp,
+    pub goal_key: Option<u64>,
 }
 
 impl Storable for ChatHistory {
@@ -120,6 +408,48 @@ impl BoundedStorable for PaymentTransaction {
     const IS_FIXED_SIZE: bool = false;
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum SubtaskStatus {
+    Pending,
+    InProgress,
+    Done,
+    Skipped,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Subtask {
+    pub description: String,
+    pub status: SubtaskStatus,
+}
+
+// A decision of the LLM waiting for the owner to step through it in debug mode
+#[derive(Clone, CandidType, Deserialize)]
+pub struct DebugStep {
+    pub prompt: String,
+    pub raw_output: String,
+    pub command: Option<String>,
+    pub num_thoughts: u16,
+    pub created_at: Timestamp,
+}
+
+// A command simulated instead of executed while the goal runs in dry run
+#[derive(Clone, CandidType, Deserialize)]
+pub struct DryRunAction {
+    pub command: String,
+    pub args: String,
+    pub result: String,
+    pub created_at: Timestamp,
+}
+
+// Guidance from the owner is shown in the next prompt of the goal, or in every prompt if sticky
+#[derive(Clone, CandidType, Deserialize)]
+pub struct GoalGuidance {
+    pub text: String,
+    pub is_sticky: bool,
+    pub is_delivered: bool,
+    pub created_at: Timestamp,
+}
+
 // Goal Struct and Storable Trait
 #[derive(CandidType, Deserialize)]
 pub struct Goal {
@@ -128,6 +458,29 @@ pub struct Goal {
     pub status: GoalStatus,
     pub created_at: Timestamp,
     pub updated_at: Timestamp,
+    pub plan: Option<Vec<Subtask>>,
+    // set for goals of child agents started by the goal with parent_goal_key
+    pub parent_goal_key: Option<u64>,
+    pub agent_name: Option<String>,
+    pub agent_task: Option<String>,
+    pub max_num_thoughts: Option<u16>,
+    // thoughts used so far, saved when the goal waits on its child agents
+    pub num_thoughts: Option<u16>,
+    pub waiting_on: Option<Vec<u64>>,
+    // None = the agent cannot ask the owner questions
+    pub is_ask_user_enabled: Option<bool>,
+    // question of a goal waiting for the owner to reply
+    pub pending_question: Option<String>,
+    pub guidance: Option<Vec<GoalGuidance>>,
+    // None = run without stopping after each decision
+    pub is_debug_mode: Option<bool>,
+    pub debug_step: Option<DebugStep>,
+    // None = commands are executed, otherwise side effects are only recorded
+    pub is_dry_run: Option<bool>,
+    pub dry_run_actions: Option<Vec<DryRunAction>>,
+    pub cost: Option<Cost>,
+    // None = only limited by the number of thoughts
+    pub budget: Option<GoalBudget>,
 }
 
 impl Storable for Goal {
@@ -145,6 +498,304 @@ impl BoundedStorable for Goal {
     const IS_FIXED_SIZE: bool = false;
 }
 
+// Embeddings generated for a content, keyed by the SHA-256 hash of the content
+#[derive(CandidType, Deserialize)]
+pub struct CachedEmbeddings {
+    pub embeddings: Embeddings,
+    pub created_at: Timestamp,
+    // None for embeddings cached before the cache was evicted by last use
+    pub last_used_at: Option<Timestamp>,
+}
+
+impl CachedEmbeddings {
+    pub fn get_last_used_at(&self) -> Timestamp {
+        self.last_used_at.unwrap_or(self.created_at)
+    }
+}
+
+impl Storable for CachedEmbeddings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CachedEmbeddings {
+    const MAX_SIZE: u32 = MAX_EMBEDDINGS_VALUE_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Knowledge Graph of entities and relations extracted from tool results
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct GraphEntity {
+    pub name: String,
+    // e.g person, organisation, token, date
+    pub entity_type: String,
+    pub num_mentions: u32,
+    pub created_at: Timestamp,
+    pub updated_at: Timestamp,
+}
+
+impl Storable for GraphEntity {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for GraphEntity {
+    const MAX_SIZE: u32 = MAX_GRAPH_VALUE_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct GraphRelation {
+    pub subject: String,
+    pub predicate: String,
+    pub object: String,
+    // google query or url the relation was extracted from
+    pub source: String,
+    pub created_at: Timestamp,
+}
+
+impl Storable for GraphRelation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for GraphRelation {
+    const MAX_SIZE: u32 = MAX_GRAPH_VALUE_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Cost Accounting
+// Tokens, HTTP outcalls and cycles spent by the brain or tools canister on a request
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub http_outcalls: u64,
+    // approximate, read from the balance which concurrent requests also spend
+    pub cycles_used: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AskResult {
+    pub content: String,
+    pub usage: Usage,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EmbeddingsResult {
+    pub embeddings: Embeddings,
+    pub usage: Usage,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ToolResult {
+    pub content: String,
+    pub usage: Usage,
+}
+
+// Structured Commands
+// Command offered to the brain as a function to call, parameters is the JSON schema of its args
+#[derive(Clone, CandidType, Deserialize)]
+pub struct CommandSchema {
+    pub name: String,
+    pub description: String,
+    pub parameters: String,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize, Serialize)]
+pub struct Thoughts {
+    pub text: String,
+    pub reasoning: String,
+    pub plan: String,
+    pub criticism: String,
+    pub speak: String,
+}
+
+// args is the JSON object of the command arguments
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Command {
+    pub name: String,
+    pub args: String,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct AgentResponse {
+    pub thoughts: Thoughts,
+    pub command: Command,
+}
+
+// Input of a step, the command called by the brain, or the command text of the controller
+// and the owner
+pub enum CofInput {
+    Text(String),
+    Response(AgentResponse),
+}
+
+// response is an error with the answer of the model when it did not call a command
+#[derive(CandidType, Deserialize)]
+pub struct ThinkResult {
+    pub response: Result<AgentResponse, String>,
+    pub usage: Usage,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Deserialize, Serialize)]
+pub struct Cost {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub embedding_tokens: u64,
+    pub http_outcalls: u64,
+    // cycles are approximate, concurrent goals and requests may count part of each other's
+    pub controller_cycles: u64,
+    pub brain_cycles: u64,
+    pub tools_cycles: u64,
+}
+
+// Cost of all goals and owner requests of one day, starting at period_start
+#[derive(Clone, CandidType, Deserialize, Serialize)]
+pub struct PeriodCost {
+    pub period_start: Timestamp,
+    pub cost: Cost,
+}
+
+// Limits of the cost of a goal including its child agents, any limit reached stops the goal
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GoalBudget {
+    // checked against the approximate cycles recorded for the goal
+    pub max_cycles: Option<u64>,
+    pub max_tokens: Option<u64>,
+    // requires the prices set by the owner
+    pub max_usd: Option<f64>,
+}
+
+// Prices set by the owner to convert costs to a USD equivalent
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct CostPrices {
+    pub usd_per_million_prompt_tokens: f64,
+    pub usd_per_million_completion_tokens: f64,
+    pub usd_per_million_embedding_tokens: f64,
+    pub usd_per_trillion_cycles: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CostEstimate {
+    pub num_goals_sampled: u64,
+    pub expected_num_steps: u64,
+    pub cost: Cost,
+    pub usd: Option<f64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CostReport {
+    pub from: Timestamp,
+    pub to: Timestamp,
+    pub total: Cost,
+    pub periods: Vec<PeriodCost>,
+}
+
+// Model Routing
+// Kinds of steps calling the brain, each routed to its own model.
+// Summarisation turns tool results into knowledge graph entities and relations.
+// JsonRepair fixes a malformed command before falling back to a recovery command.
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum StepKind {
+    Main,
+    WebExtraction,
+    Summarisation,
+    Reflection,
+    ImportanceScoring,
+    JsonRepair,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ModelRoute {
+    pub step_kind: StepKind,
+    // None = the default model of the brain
+    pub model: Option<String>,
+    // None = the default temperature of the brain
+    pub temperature: Option<f32>,
+}
+
+// Stronger model used by main reasoning steps after repeated invalid command recoveries
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ModelEscalation {
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub after_num_recoveries: u16,
+}
+
+// Step Trace
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub enum StepOutcome {
+    Ok,
+    Error,
+    Recovery,
+}
+
+// One step of the chain of thoughts of a goal, recorded when the step ends
+#[derive(Clone, CandidType, Deserialize)]
+pub struct StepTrace {
+    pub goal_key: u64,
+    pub step: u16,
+    // None = the model response was not a valid command
+    pub command: Option<String>,
+    pub args: String,
+    pub result_size: u64,
+    // None = the step did not call the brain
+    pub model: Option<String>,
+    pub latency_ns: u64,
+    // approximate, steps of goals running at the same time may count each other's cycles
+    pub cycles_used: u64,
+    pub outcome: StepOutcome,
+    pub created_at: Timestamp,
+}
+
+impl Storable for StepTrace {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StepTrace {
+    const MAX_SIZE: u32 = MAX_STEP_TRACE_VALUE_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, PartialEq)]
+pub struct CommandTraceStats {
+    pub command: String,
+    pub num_steps: u64,
+    pub num_errors: u64,
+    pub num_recoveries: u64,
+    pub total_latency_ns: u64,
+    pub max_latency_ns: u64,
+    pub total_cycles_used: u64,
+}
+
+#[derive(CandidType, Deserialize, Serialize)]
+pub struct GraphQueryResult {
+    pub entity: GraphEntity,
+    pub relations: Vec<GraphRelation>,
+}
+
 // HTTP
 #[derive(CandidType, Deserialize, Clone)]
 pub struct HeaderField(pub String, pub String);
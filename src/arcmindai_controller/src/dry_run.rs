@@ -0,0 +1,85 @@
+use crate::datatype::{DryRunAction, DryRunFixture, PROMPT_CMD_BEAMFI_STREAM_PAYMENT};
+
+// commands with effects outside the controller, only recorded in dry run
+pub const SIDE_EFFECT_COMMANDS: [&str; 1] = [PROMPT_CMD_BEAMFI_STREAM_PAYMENT];
+
+fn normalize(text: &str) -> String {
+    text.trim().to_lowercase()
+}
+
+// Inputs of google queries and browsed urls are matched case-insensitively
+pub fn is_fixture_match(fixture: &DryRunFixture, command: &str, input: &str) -> bool {
+    fixture.command == command && normalize(&fixture.input) == normalize(input)
+}
+
+pub fn find_fixture_output(
+    fixtures: &[DryRunFixture],
+    command: &str,
+    input: &str,
+) -> Option<String> {
+    fixtures
+        .iter()
+        .find(|fixture| is_fixture_match(fixture, command, input))
+        .map(|fixture| fixture.output.clone())
+}
+
+// Summary of the commands simulated during a dry run, appended to the goal result
+pub fn format_dry_run_report(actions: &[DryRunAction]) -> String {
+    if actions.is_empty() {
+        return "Dry run report: no command was simulated.".to_string();
+    }
+
+    let lines: Vec<String> = actions
+        .iter()
+        .enumerate()
+        .map(|(i, action)| {
+            format!(
+                "{}. {} {} -> {}",
+                i + 1,
+                action.command,
+                action.args,
+                action.result
+            )
+        })
+        .collect();
+
+    format!(
+        "Dry run report: {} commands were simulated instead of executed:\n{}",
+        actions.len(),
+        lines.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_fixture_output, format_dry_run_report};
+    use crate::datatype::{DryRunAction, DryRunFixture};
+
+    #[test]
+    fn finds_fixture_and_formats_report() {
+        let fixtures = vec![DryRunFixture {
+            command: "google".to_string(),
+            input: "ICP price".to_string(),
+            output: "ICP is at $12".to_string(),
+        }];
+        assert_eq!(
+            find_fixture_output(&fixtures, "google", " icp PRICE "),
+            Some("ICP is at $12".to_string())
+        );
+        assert_eq!(
+            find_fixture_output(&fixtures, "browse_website", "ICP price"),
+            None
+        );
+
+        let actions = vec![DryRunAction {
+            command: "beamfi_stream_payment".to_string(),
+            args: r#"{"amount":"10"}"#.to_string(),
+            result: "Not executed".to_string(),
+            created_at: 0,
+        }];
+        assert_eq!(
+            format_dry_run_report(&actions),
+            "Dry run report: 1 commands were simulated instead of executed:\n1. beamfi_stream_payment {\"amount\":\"10\"} -> Not executed"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use serde_json::Value;
+
+use crate::datatype::CommandSchema;
+
+// The outermost JSON object of the text, without the prose or code fences around it
+pub fn extract_json_object(text: &str) -> Option<&str> {
+    let start = text.find('{')?;
+    let mut depth: usize = 0;
+    let mut quote: Option<char> = None;
+    let mut is_escaped = false;
+
+    for (i, c) in text[start..].char_indices() {
+        if let Some(q) = quote {
+            if is_escaped {
+                is_escaped = false;
+            } else if c == '\\' {
+                is_escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => quote = Some(c),
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&text[start..=start + i]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // unbalanced quotes or braces, keep everything up to the last brace
+    let end = text.rfind('}')?;
+    (start < end).then(|| &text[start..=end])
+}
+
+/*
+ * Repairs the common errors of JSON written by a model:
+ * single quoted strings, trailing commas and raw line breaks in strings.
+ */
+pub fn repair_json(json: &str) -> String {
+    let chars: Vec<char> = json.chars().collect();
+    let mut repaired = String::with_capacity(json.len());
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match quote {
+            Some(q) => match c {
+                '\\' if i + 1 < chars.len() => {
+                    // \' is not a valid escape in double quoted strings
+                    if chars[i + 1] != '\'' {
+                        repaired.push(c);
+                    }
+                    repaired.push(chars[i + 1]);
+                    i += 1;
+                }
+                '"' if q == '\'' => repaired.push_str("\\\""),
+                '\n' => repaired.push_str("\\n"),
+                '\r' => repaired.push_str("\\r"),
+                '\t' => repaired.push_str("\\t"),
+                _ if c == q => {
+                    repaired.push('"');
+                    quote = None;
+                }
+                _ => repaired.push(c),
+            },
+            None => match c {
+                '"' | '\'' => {
+                    repaired.push('"');
+                    quote = Some(c);
+                }
+                ',' => {
+                    let next = chars[i + 1..].iter().find(|c| !c.is_whitespace());
+                    if !matches!(next, Some('}') | Some(']')) {
+                        repaired.push(c);
+                    }
+                }
+                _ => repaired.push(c),
+            },
+        }
+        i += 1;
+    }
+
+    repaired
+}
+
+// Parses the JSON object of a model response, extracting and repairing it when needed
+pub fn parse_json_object(text: &str) -> Result<Value, String> {
+    if let Ok(value @ Value::Object(_)) = serde_json::from_str::<Value>(text) {
+        return Ok(value);
+    }
+
+    let json = extract_json_object(text).ok_or("No JSON object found.".to_string())?;
+    serde_json::from_str::<Value>(&repair_json(json)).map_err(|e| format!("Invalid JSON: {}", e))
+}
+
+// Checks the command has a name, and the required args of its schema
+pub fn validate_command(cof_json: &Value, commands: &[CommandSchema]) -> Result<(), String> {
+    let name = cof_json["command"]["name"]
+        .as_str()
+        .ok_or("Missing command name.".to_string())?;
+
+    validate_command_args(name, &cof_json["command"]["args"], commands)
+}
+
+// Checks the required args of the schema of the command.
+// Commands without a schema are left to the chain of thoughts to report.
+pub fn validate_command_args(
+    name: &str,
+    args: &Value,
+    commands: &[CommandSchema],
+) -> Result<(), String> {
+    let schema = match commands.iter().find(|command| command.name == name) {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+
+    let args = args
+        .as_object()
+        .ok_or(format!("Missing args of command {}.", name))?;
+    let parameters: Value = serde_json::from_str(&schema.parameters).unwrap_or_default();
+    let missing_args: Vec<&str> = parameters["required"]
+        .as_array()
+        .map(|required| {
+            required
+                .iter()
+                .filter_map(|arg| arg.as_str())
+                .filter(|arg| !args.contains_key(*arg))
+                .collect()
+        })
+        .unwrap_or_default();
+    if !missing_args.is_empty() {
+        return Err(format!(
+            "Missing args of command {}: {}.",
+            name,
+            missing_args.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_json_object, validate_command};
+    use crate::commands::create_command_schemas;
+
+    #[test]
+    fn repairs_and_validates_model_json() {
+        let response = "Here is my next command:\n```json\n{\n  'thoughts': {'speak': \"I'm searching\",},\n  \"command\": {\"name\": \"google\", \"args\": {\"query\": \"json {spec}\"},},\n}\n```\nLet me know!";
+        let cof_json = parse_json_object(response).unwrap();
+        assert_eq!(cof_json["thoughts"]["speak"], "I'm searching");
+        assert_eq!(cof_json["command"]["args"]["query"], "json {spec}");
+
+        let commands = create_command_schemas(false);
+        assert!(validate_command(&cof_json, &commands).is_ok());
+
+        let cof_json = parse_json_object("{'command': {'name': 'google', 'args': {}}}").unwrap();
+        assert_eq!(
+            validate_command(&cof_json, &commands),
+            Err("Missing args of command google: query.".to_string())
+        );
+
+        let cof_json = parse_json_object(r#"{"command": {"name": "do_nothing"}}"#).unwrap();
+        assert!(validate_command(&cof_json, &commands).is_ok());
+
+        assert!(parse_json_object("I cannot decide on a command.").is_err());
+    }
+}
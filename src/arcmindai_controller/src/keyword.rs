@@ -0,0 +1,128 @@
+use crate::datatype::{HybridSearchWeights, PlainDoc};
+
+// BM25 term frequency saturation and document length normalisation
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+
+// Lowercased words, keeping the dashes and underscores of principal ids and symbols
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+        .map(|token| token.trim_matches(|c| c == '-' || c == '_').to_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect()
+}
+
+// BM25 score of each document for the query, with document frequencies of the given documents
+pub fn score_bm25(query: &str, docs: &[&str]) -> Vec<f32> {
+    let query_tokens: Vec<String> = tokenize(query);
+    let doc_tokens: Vec<Vec<String>> = docs.iter().map(|doc| tokenize(doc)).collect();
+    if doc_tokens.is_empty() {
+        return Vec::new();
+    }
+
+    let num_docs = doc_tokens.len() as f32;
+    let avg_doc_len: f32 = doc_tokens.iter().map(|t| t.len() as f32).sum::<f32>() / num_docs;
+
+    doc_tokens
+        .iter()
+        .map(|tokens| {
+            let doc_len = tokens.len() as f32;
+            query_tokens
+                .iter()
+                .map(|query_token| {
+                    let term_freq = tokens.iter().filter(|t| *t == query_token).count() as f32;
+                    if term_freq == 0.0 {
+                        return 0.0;
+                    }
+                    let doc_freq = doc_tokens
+                        .iter()
+                        .filter(|tokens| tokens.contains(query_token))
+                        .count() as f32;
+                    let idf = ((num_docs - doc_freq + 0.5) / (doc_freq + 0.5) + 1.0).ln();
+                    let length_norm =
+                        1.0 - BM25_B + BM25_B * doc_len / avg_doc_len.max(f32::EPSILON);
+                    idf * term_freq * (BM25_K1 + 1.0) / (term_freq + BM25_K1 * length_norm)
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/*
+ * Re-scores vector search results by a weighted sum of their similarity and their
+ * BM25 keyword score of the query text, normalised to 0.0 - 1.0 by the best match.
+ * Results without a similarity score are scored by their position in the search results.
+ */
+pub fn apply_hybrid_scores(
+    text: &str,
+    docs: Vec<PlainDoc>,
+    weights: &HybridSearchWeights,
+) -> Vec<PlainDoc> {
+    let contents: Vec<&str> = docs.iter().map(|doc| doc.content.as_str()).collect();
+    let keyword_scores: Vec<f32> = score_bm25(text, &contents);
+    let max_keyword_score: f32 = keyword_scores.iter().cloned().fold(0.0, f32::max);
+    let num_docs = docs.len() as f32;
+
+    docs.into_iter()
+        .zip(keyword_scores)
+        .enumerate()
+        .map(|(i, (doc, keyword_score))| {
+            let similarity = doc.score.unwrap_or(1.0 - i as f32 / num_docs);
+            let keyword_score = if max_keyword_score > 0.0 {
+                keyword_score / max_keyword_score
+            } else {
+                0.0
+            };
+            PlainDoc {
+                score: Some(
+                    weights.vector_weight * similarity + weights.keyword_weight * keyword_score,
+                ),
+                ..doc
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_hybrid_scores, tokenize};
+    use crate::datatype::{HybridSearchWeights, PlainDoc};
+
+    fn create_doc(content: &str, score: f32) -> PlainDoc {
+        PlainDoc {
+            id: None,
+            content: content.to_string(),
+            parent_id: None,
+            chunk_index: None,
+            score: Some(score),
+            importance: None,
+            created_at: None,
+            last_accessed_at: None,
+        }
+    }
+
+    #[test]
+    fn boosts_exact_keyword_matches() {
+        assert_eq!(
+            tokenize("Send ICP to rrkah-fqaaa-aaaaa-aaaaq-cai."),
+            vec!["send", "icp", "to", "rrkah-fqaaa-aaaaa-aaaaq-cai"]
+        );
+
+        let docs = vec![
+            create_doc("The internet computer price rose today.", 0.9),
+            create_doc(
+                "Canister rrkah-fqaaa-aaaaa-aaaaq-cai holds the ledger.",
+                0.7,
+            ),
+        ];
+        let weights = HybridSearchWeights {
+            keyword_weight: 0.5,
+            vector_weight: 0.5,
+        };
+
+        let docs = apply_hybrid_scores("ledger of rrkah-fqaaa-aaaaa-aaaaq-cai", docs, &weights);
+
+        assert_eq!(docs[0].score, Some(0.45));
+        assert_eq!(docs[1].score, Some(0.85));
+    }
+}
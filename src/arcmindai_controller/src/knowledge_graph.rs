@@ -0,0 +1,264 @@
+use ic_cdk::api::time;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::datatype::{
+    GraphEntity, GraphQueryResult, GraphRelation, Timestamp, MAX_GRAPH_TEXT_BYTES,
+};
+use crate::STATE;
+
+// Entities and relations as answered by the LLM for GRAPH_EXTRACTION_PROMPT
+#[derive(Deserialize, Default)]
+pub struct GraphExtraction {
+    #[serde(default)]
+    pub entities: Vec<ExtractedEntity>,
+    #[serde(default)]
+    pub relations: Vec<ExtractedRelation>,
+}
+
+#[derive(Deserialize)]
+pub struct ExtractedEntity {
+    pub name: String,
+    #[serde(rename = "type", default)]
+    pub entity_type: String,
+}
+
+#[derive(Deserialize)]
+pub struct ExtractedRelation {
+    pub subject: String,
+    pub predicate: String,
+    pub object: String,
+}
+
+// Trimmed and truncated to MAX_GRAPH_TEXT_BYTES at a char boundary
+fn normalize(text: &str) -> String {
+    let text = text.trim();
+    let mut end = text.len().min(MAX_GRAPH_TEXT_BYTES);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].trim_end().to_string()
+}
+
+fn is_same_name(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+// Entities and relations are deduplicated by the case-insensitive hash of their names
+fn create_graph_key(names: &[&str]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for name in names {
+        hasher.update(name.to_lowercase().as_bytes());
+        hasher.update([0]);
+    }
+    hasher.finalize().into()
+}
+
+fn create_relation_key(relation: &GraphRelation) -> [u8; 32] {
+    create_graph_key(&[&relation.subject, &relation.predicate, &relation.object])
+}
+
+// Indexes entities and relations saved before they were indexed by their keys
+pub fn index_knowledge_graph() {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+
+        if state.stable_graph_entity_index_data.is_empty() {
+            let entity_keys: Vec<[u8; 32]> = state
+                .stable_graph_entity_data
+                .iter()
+                .map(|entity| create_graph_key(&[&entity.name]))
+                .collect();
+            for (index, key) in entity_keys.into_iter().enumerate() {
+                state
+                    .stable_graph_entity_index_data
+                    .insert(key, index as u64);
+            }
+        }
+
+        if state.stable_graph_relation_index_data.is_empty() {
+            let relation_keys: Vec<[u8; 32]> = state
+                .stable_graph_relation_data
+                .iter()
+                .map(|relation| create_relation_key(&relation))
+                .collect();
+            for (index, key) in relation_keys.into_iter().enumerate() {
+                state
+                    .stable_graph_relation_index_data
+                    .insert(key, index as u64);
+            }
+        }
+    });
+}
+
+// Parses the JSON object in the LLM answer, ignoring any text around it
+pub fn parse_graph_extraction(answer: &str) -> GraphExtraction {
+    let start = answer.find('{');
+    let end = answer.rfind('}');
+
+    match (start, end) {
+        (Some(start), Some(end)) if start < end => {
+            serde_json::from_str(&answer[start..=end]).unwrap_or_default()
+        }
+        _ => GraphExtraction::default(),
+    }
+}
+
+// Adds new entities and relations, counting repeated mentions of known entities
+pub fn upsert_graph_extraction(extraction: GraphExtraction, source: &str) {
+    let now: Timestamp = time();
+
+    for extracted_entity in extraction.entities {
+        let name = normalize(&extracted_entity.name);
+        if name.is_empty() {
+            continue;
+        }
+        upsert_entity(name, normalize(&extracted_entity.entity_type), now);
+    }
+
+    for extracted_relation in extraction.relations {
+        let relation = GraphRelation {
+            subject: normalize(&extracted_relation.subject),
+            predicate: normalize(&extracted_relation.predicate),
+            object: normalize(&extracted_relation.object),
+            source: normalize(source),
+            created_at: now,
+        };
+        if relation.subject.is_empty() || relation.predicate.is_empty() {
+            continue;
+        }
+
+        let key: [u8; 32] = create_relation_key(&relation);
+        let is_existing = STATE.with(|s| {
+            s.borrow()
+                .stable_graph_relation_index_data
+                .contains_key(&key)
+        });
+        if is_existing {
+            continue;
+        }
+
+        // relations may mention entities that were not extracted on their own
+        upsert_entity(relation.subject.clone(), "".to_string(), now);
+
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            let index: u64 = state.stable_graph_relation_data.len();
+            match state.stable_graph_relation_data.push(&relation) {
+                Ok(()) => {
+                    state.stable_graph_relation_index_data.insert(key, index);
+                }
+                Err(e) => ic_cdk::println!("Failed to save graph relation: {:?}", e),
+            }
+        });
+    }
+}
+
+fn find_entity(name: &str) -> Option<(u64, GraphEntity)> {
+    let key: [u8; 32] = create_graph_key(&[name]);
+    STATE.with(|s| {
+        let state = s.borrow();
+        let index: u64 = state.stable_graph_entity_index_data.get(&key)?;
+        state
+            .stable_graph_entity_data
+            .get(index)
+            .map(|entity| (index, entity))
+    })
+}
+
+fn upsert_entity(name: String, entity_type: String, now: Timestamp) {
+    let existing: Option<(u64, GraphEntity)> = find_entity(&name);
+
+    match existing {
+        Some((index, entity)) => {
+            let updated_entity = GraphEntity {
+                entity_type: if entity.entity_type.is_empty() {
+                    entity_type
+                } else {
+                    entity.entity_type.clone()
+                },
+                num_mentions: entity.num_mentions + 1,
+                updated_at: now,
+                ..entity
+            };
+            STATE.with(|s| {
+                s.borrow_mut()
+                    .stable_graph_entity_data
+                    .set(index, &updated_entity)
+            });
+        }
+        None => {
+            let new_entity = GraphEntity {
+                name,
+                entity_type,
+                num_mentions: 1,
+                created_at: now,
+                updated_at: now,
+            };
+            let key: [u8; 32] = create_graph_key(&[&new_entity.name]);
+            STATE.with(|s| {
+                let mut state = s.borrow_mut();
+                let index: u64 = state.stable_graph_entity_data.len();
+                match state.stable_graph_entity_data.push(&new_entity) {
+                    Ok(()) => {
+                        state.stable_graph_entity_index_data.insert(key, index);
+                    }
+                    Err(e) => ic_cdk::println!("Failed to save graph entity: {:?}", e),
+                }
+            });
+        }
+    }
+}
+
+// Returns the entity and all relations it is the subject or object of
+pub fn query_graph_entity(name: &str) -> Option<GraphQueryResult> {
+    let name = normalize(name);
+    let entity: Option<GraphEntity> = find_entity(&name).map(|(_, entity)| entity);
+
+    entity.map(|entity| {
+        let relations: Vec<GraphRelation> = STATE.with(|s| {
+            s.borrow()
+                .stable_graph_relation_data
+                .iter()
+                .filter(|r| is_same_name(&r.subject, &name) || is_same_name(&r.object, &name))
+                .collect()
+        });
+
+        GraphQueryResult { entity, relations }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, parse_graph_extraction};
+    use crate::datatype::MAX_GRAPH_TEXT_BYTES;
+
+    #[test]
+    fn parses_extraction_with_text_around() {
+        let answer = r#"Here is the graph: { "entities": [{ "name": "DFINITY", "type": "organisation" }], "relations": [{ "subject": "DFINITY", "predicate": "develops", "object": "Internet Computer" }] } Hope it helps"#;
+
+        let extraction = parse_graph_extraction(answer);
+
+        assert_eq!(extraction.entities.len(), 1);
+        assert_eq!(extraction.entities[0].entity_type, "organisation");
+        assert_eq!(extraction.relations[0].object, "Internet Computer");
+    }
+
+    #[test]
+    fn invalid_extraction_is_empty() {
+        let extraction = parse_graph_extraction("no entities found");
+
+        assert!(extraction.entities.is_empty());
+        assert!(extraction.relations.is_empty());
+    }
+
+    #[test]
+    fn truncates_text_at_char_boundary() {
+        let text = "é".repeat(MAX_GRAPH_TEXT_BYTES);
+
+        let normalized = normalize(&text);
+
+        assert_eq!(normalized.len(), MAX_GRAPH_TEXT_BYTES);
+        assert_eq!(normalize(" DFINITY "), "DFINITY");
+    }
+}
@@ -3,6 +3,7 @@ use plugin_types::AMPluginAction;
 use serde_json::json;
 use std::{
     cell::RefCell,
+    collections::HashMap,
     sync::atomic::{AtomicU64, Ordering},
     time::Duration,
 };
@@ -10,27 +11,102 @@ use std::{
 use time::format_description;
 use time::OffsetDateTime;
 
+use futures::future::join_all;
 use ic_cdk::api::time;
 use ic_cdk_timers::TimerId;
-use ic_stable_structures::{writer::Writer, Memory as _, StableVec};
+use ic_stable_structures::{writer::Writer, Memory as _, StableBTreeMap, StableVec};
+use sha2::{Digest, Sha256};
 
 mod datatype;
 use datatype::{
-    ChatDisplayHistory, ChatHistory, ChatRole, Embeddings, Goal, GoalStatus, HttpRequest,
-    HttpResponse, PaymentTransaction, PlainDoc, PromptContext, Timestamp, VecDoc, VecQuery,
-    WebQueryPromptContext, PROMPT_CMD_BEAMFI_STREAM_PAYMENT, PROMPT_CMD_BROWSE_WEBSITE,
-    PROMPT_CMD_DO_NOTHING, PROMPT_CMD_GOOGLE, PROMPT_CMD_SHUTDOWN, PROMPT_CMD_START_AGENT,
-    PROMPT_CMD_WRITE_FILE_AND_SHUTDOWN, TOP_CMD_AGENT_NAME, TOP_CMD_AGENT_TASK,
-    VEC_SEARCH_TOP_K_NN,
+    AgentResponse, AskResult, CachedEmbeddings, ChatDisplayHistory, ChatHistory, ChatRole,
+    CofInput, CommandSchema, CommandTraceStats, Cost, CostEstimate, CostPrices, CostReport,
+    DebugStep, DocumentFormat, DocumentStatus, DocumentUpload, DryRunAction, DryRunFixture,
+    Embeddings, EmbeddingsResult, FadeAction, Goal, GoalBudget, GoalGuidance, GoalSchedule,
+    GoalStatus, GraphEntity, GraphExtractionPromptContext, GraphQueryResult, GraphRelation,
+    HttpRequest, HttpResponse, HybridSearchWeights, JsonRepairPromptContext, KnowledgeDocument,
+    MemoryDoc, MemoryFadePolicy, MemoryImportancePromptContext, MemoryPage, ModelEscalation,
+    ModelRoute, PaymentTransaction, PeriodCost, PlainDoc, PromptContext, ReflectionPromptContext,
+    RetrievalWeights, ScheduleRun, ScheduleTrigger, StepKind, StepOutcome, StepTrace, Subtask,
+    ThinkResult, Timestamp, ToolResult, Usage, VecDoc, VecQuery, WatchSource, Watcher,
+    WatcherContent, WebQueryPromptContext, Workflow, WorkflowNode, WorkflowNodeProgress,
+    WorkflowRun, WorkflowRunNode, WorkflowRunProgress, WorkflowRunStatus, MAX_MEMORY_PAGE_SIZE,
+    PROMPT_CMD_ASK_USER, PROMPT_CMD_BEAMFI_STREAM_PAYMENT, PROMPT_CMD_BROWSE_WEBSITE,
+    PROMPT_CMD_DO_NOTHING, PROMPT_CMD_GOOGLE, PROMPT_CMD_GRAPH_QUERY, PROMPT_CMD_SET_PLAN,
+    PROMPT_CMD_SHUTDOWN, PROMPT_CMD_START_AGENT, PROMPT_CMD_UPDATE_SUBTASK,
+    PROMPT_CMD_WAIT_FOR_AGENTS, PROMPT_CMD_WRITE_FILE_AND_SHUTDOWN, TOP_CMD_AGENT_NAME,
+    TOP_CMD_AGENT_TASK, VEC_NAMESPACE_DOCUMENT, VEC_NAMESPACE_LESSON, VEC_SEARCH_NUM_CANDIDATES,
+    VEC_SEARCH_NUM_HYBRID_CANDIDATES, VEC_SEARCH_TOP_K_LESSONS, VEC_SEARCH_TOP_K_NN,
 };
 
+mod chunker;
+use chunker::{chunk_content, join_chunks, Chunk, CHUNK_MAX_TOKENS, CHUNK_OVERLAP_TOKENS};
+
 mod prompts;
-use prompts::{COF_PROMPT, RESPONSE_FORMAT, WEB_QUERY_PROMPT};
+use prompts::{
+    render_unescaped_prompt, COF_PROMPT, GRAPH_EXTRACTION_PROMPT, JSON_REPAIR_PROMPT,
+    MEMORY_IMPORTANCE_PROMPT, REFLECTION_PROMPT, RESPONSE_FORMAT, WEB_QUERY_PROMPT,
+};
+
+mod knowledge_graph;
+use knowledge_graph::{
+    index_knowledge_graph, parse_graph_extraction, query_graph_entity, upsert_graph_extraction,
+};
+
+mod planner;
+use planner::{format_plan, parse_subtask_status, parse_subtasks};
+
+mod workflow;
+use workflow::{find_ready_nodes, render_goal_template, validate_workflow_nodes};
+
+mod scheduler;
+use scheduler::{next_run_at, render_schedule_goal};
+
+mod watcher;
+use watcher::{
+    diff_lines, extract_watch_lines, fingerprint_lines, is_tool_error, render_watcher_goal,
+    MIN_WATCH_INTERVAL_SECS,
+};
+
+mod dry_run;
+use dry_run::{find_fixture_output, format_dry_run_report, is_fixture_match, SIDE_EFFECT_COMMANDS};
+
+mod cost;
+use cost::{add_cost, add_period_cost, create_usage_cost, get_period_start, CostSource};
+
+mod budget;
+use budget::{create_partial_result, estimate_goal_cost, find_exceeded_budget};
+
+mod commands;
+use commands::{
+    create_agent_command, create_command_schemas, format_agent_response, validate_agent_response,
+};
+
+mod json_repair;
+use json_repair::{parse_json_object, validate_command};
+
+mod routing;
+use routing::{
+    count_trailing_recoveries, escalate_model_route, find_model_route, validate_model_route,
+};
+
+mod trace;
+use trace::{summarize_step_traces, truncate_trace_args, PendingStepTrace};
+
+mod keyword;
+use keyword::apply_hybrid_scores;
+
+mod retrieval;
+use retrieval::{
+    default_retrieval_weights, parse_importance, rank_memories, retention_score,
+    DEFAULT_MEMORY_IMPORTANCE,
+};
 
 extern crate tinytemplate;
 use tinytemplate::TinyTemplate;
 
 // Candid
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{candid_method, Principal};
 
 use ic_cdk::{
@@ -66,8 +142,36 @@ const CYCLES_TOPUP_GROUP: &str = "arcmindai_controller";
 const RECENT_CHAT_HISTORY: usize = 80;
 const DATE_TIME_FORMAT: &str = "[year]-[month]-[day] [hour]:[minute]:[second]";
 const MAX_NUM_COF_PER_GOAL: u16 = 100;
+const MAX_NUM_COF_PER_CHILD_AGENT: u16 = 20;
+const MAX_NUM_CHILD_AGENTS_PER_GOAL: usize = 5;
+// agents that have not finished by then fail, e.g. when a step of the agent trapped
+const WAIT_FOR_AGENTS_TIMEOUT_SECS: u64 = 60 * 60;
+const AGENT_TIMEOUT_CHECK_INTERVAL_SECS: u64 = 60;
+// runs fail when the goal of a node has not completed by then, e.g. when a step trapped
+const WORKFLOW_NODE_TIMEOUT_SECS: u64 = 60 * 60 * 24;
 const DEFAULT_MAX_NUM_THOUGHTS_ALLOWED: u16 = 500;
 
+// 1 day
+const MEMORY_FADE_CHECK_INTERVAL_SECS: u64 = 60 * 60 * 24;
+// cron schedules have a resolution of one minute
+const SCHEDULE_CHECK_INTERVAL_SECS: u64 = 60;
+const MAX_NUM_SCHEDULE_RUNS: usize = 100;
+const WATCHER_CHECK_INTERVAL_SECS: u64 = 60;
+// model recorded in step traces when the brain uses its own default model
+const BRAIN_DEFAULT_MODEL: &str = "default";
+// owner seeded documents and lessons learned are always fully important
+const DOCUMENT_IMPORTANCE: f32 = 1.0;
+const LESSON_IMPORTANCE: f32 = 1.0;
+// max chars of each message in the transcript reflected on after a goal
+const MAX_REFLECTION_MESSAGE_CHARS: usize = 1000;
+// only the beginning of long content is needed to rate its importance
+const MAX_IMPORTANCE_PROMPT_CHARS: usize = 8000;
+
+// ~300MB of ada-002 embeddings
+const MAX_EMBEDDINGS_CACHE_SIZE: u64 = 50_000;
+// cosine similarity above which a new memory is a near-duplicate of an existing one
+const DUPLICATE_SIMILARITY_THRESHOLD: f32 = 0.97;
+
 #[derive(Serialize, Deserialize)]
 pub struct State {
     pub owner: Option<Principal>,
@@ -86,6 +190,50 @@ pub struct State {
     pub num_thoughts_processed: u64,
     pub billing_key: Option<String>,
 
+    // the vector canister implements the memory API beyond add and search,
+    // e.g list, pin, touch, get_chunks and search_namespace
+    #[serde(default)]
+    pub is_vector_memory_api_enabled: bool,
+    // None = pure embeddings search
+    pub hybrid_search_weights: Option<HybridSearchWeights>,
+    // None = equal weights of similarity, recency and importance
+    pub retrieval_weights: Option<RetrievalWeights>,
+    // None = memories never fade
+    pub memory_fade_policy: Option<MemoryFadePolicy>,
+
+    #[serde(default)]
+    pub knowledge_documents: Vec<KnowledgeDocument>,
+    #[serde(default)]
+    pub document_uploads: Vec<DocumentUpload>,
+
+    #[serde(default)]
+    pub workflows: Vec<Workflow>,
+    #[serde(default)]
+    pub workflow_runs: Vec<WorkflowRun>,
+
+    #[serde(default)]
+    pub goal_schedules: Vec<GoalSchedule>,
+
+    #[serde(default)]
+    pub watchers: Vec<Watcher>,
+    #[serde(default)]
+    pub watcher_contents: Vec<WatcherContent>,
+
+    #[serde(default)]
+    pub dry_run_fixtures: Vec<DryRunFixture>,
+
+    #[serde(default)]
+    pub period_costs: Vec<PeriodCost>,
+    // None = budgets in USD are not enforced
+    #[serde(default)]
+    pub cost_prices: Option<CostPrices>,
+
+    #[serde(default)]
+    pub model_routes: Vec<ModelRoute>,
+    // None = main reasoning steps keep their model after recoveries
+    #[serde(default)]
+    pub model_escalation: Option<ModelEscalation>,
+
     #[serde(skip, default = "init_stable_goal_data")]
     stable_goal_data: StableVec<Goal, Memory>,
 
@@ -94,6 +242,34 @@ pub struct State {
 
     #[serde(skip, default = "init_stable_paymenttransaction_data")]
     stable_paymenttransaction_data: StableVec<PaymentTransaction, Memory>,
+
+    #[serde(skip, default = "init_stable_embeddings_cache_data")]
+    stable_embeddings_cache_data: StableBTreeMap<[u8; 32], CachedEmbeddings, Memory>,
+
+    // Content hashes of the cached embeddings by their last use, least recently used first
+    #[serde(skip, default = "init_stable_embeddings_cache_usage_data")]
+    stable_embeddings_cache_usage_data: StableBTreeMap<(Timestamp, [u8; 32]), (), Memory>,
+
+    #[serde(skip, default = "init_stable_graph_entity_data")]
+    stable_graph_entity_data: StableVec<GraphEntity, Memory>,
+
+    #[serde(skip, default = "init_stable_graph_relation_data")]
+    stable_graph_relation_data: StableVec<GraphRelation, Memory>,
+
+    // Index in stable_graph_entity_data by the key of the entity name
+    #[serde(skip, default = "init_stable_graph_entity_index_data")]
+    stable_graph_entity_index_data: StableBTreeMap<[u8; 32], u64, Memory>,
+
+    // Index in stable_graph_relation_data by the key of the subject, predicate and object
+    #[serde(skip, default = "init_stable_graph_relation_index_data")]
+    stable_graph_relation_index_data: StableBTreeMap<[u8; 32], u64, Memory>,
+
+    #[serde(skip, default = "init_stable_step_trace_data")]
+    stable_step_trace_data: StableVec<StepTrace, Memory>,
+
+    // Indexes in stable_step_trace_data of the traces of each goal, by (goal_key, index)
+    #[serde(skip, default = "init_stable_step_trace_index_data")]
+    stable_step_trace_index_data: StableBTreeMap<(u64, u64), (), Memory>,
 }
 
 impl Default for State {
@@ -111,9 +287,33 @@ impl Default for State {
             max_num_thoughts_allowed: DEFAULT_MAX_NUM_THOUGHTS_ALLOWED as u64,
             num_thoughts_processed: 0,
             billing_key: None,
+            is_vector_memory_api_enabled: false,
+            hybrid_search_weights: None,
+            retrieval_weights: None,
+            memory_fade_policy: None,
+            knowledge_documents: Vec::new(),
+            document_uploads: Vec::new(),
+            workflows: Vec::new(),
+            workflow_runs: Vec::new(),
+            goal_schedules: Vec::new(),
+            watchers: Vec::new(),
+            watcher_contents: Vec::new(),
+            dry_run_fixtures: Vec::new(),
+            period_costs: Vec::new(),
+            cost_prices: None,
+            model_routes: Vec::new(),
+            model_escalation: None,
             stable_goal_data: init_stable_goal_data(),
             stable_chathistory_data: init_stable_chathistory_data(),
             stable_paymenttransaction_data: init_stable_paymenttransaction_data(),
+            stable_embeddings_cache_data: init_stable_embeddings_cache_data(),
+            stable_embeddings_cache_usage_data: init_stable_embeddings_cache_usage_data(),
+            stable_graph_entity_data: init_stable_graph_entity_data(),
+            stable_graph_relation_data: init_stable_graph_relation_data(),
+            stable_graph_entity_index_data: init_stable_graph_entity_index_data(),
+            stable_graph_relation_index_data: init_stable_graph_relation_index_data(),
+            stable_step_trace_data: init_stable_step_trace_data(),
+            stable_step_trace_index_data: init_stable_step_trace_index_data(),
         }
     }
 }
@@ -124,6 +324,10 @@ thread_local! {
 
     /// The global vector to keep multiple timer IDs.
     static TIMER_IDS: RefCell<Vec<TimerId>> = RefCell::new(Vec::new());
+
+    /// The step being traced of each running goal, by goal key.
+    static PENDING_STEP_TRACES: RefCell<HashMap<u64, PendingStepTrace>> =
+        RefCell::new(HashMap::new());
 }
 
 fn init_stable_goal_data() -> StableVec<Goal, Memory> {
@@ -141,6 +345,41 @@ fn init_stable_paymenttransaction_data() -> StableVec<PaymentTransaction, Memory
         .expect("call to init_stable_paymenttransaction_data fails")
 }
 
+fn init_stable_embeddings_cache_data() -> StableBTreeMap<[u8; 32], CachedEmbeddings, Memory> {
+    StableBTreeMap::init(memory::get_stable_embeddings_cache_map_memory())
+}
+
+fn init_stable_embeddings_cache_usage_data() -> StableBTreeMap<(Timestamp, [u8; 32]), (), Memory> {
+    StableBTreeMap::init(memory::get_stable_embeddings_cache_usage_map_memory())
+}
+
+fn init_stable_graph_entity_data() -> StableVec<GraphEntity, Memory> {
+    StableVec::init(memory::get_stable_graph_entity_vec_memory())
+        .expect("call to init_stable_graph_entity_data fails")
+}
+
+fn init_stable_graph_relation_data() -> StableVec<GraphRelation, Memory> {
+    StableVec::init(memory::get_stable_graph_relation_vec_memory())
+        .expect("call to init_stable_graph_relation_data fails")
+}
+
+fn init_stable_graph_entity_index_data() -> StableBTreeMap<[u8; 32], u64, Memory> {
+    StableBTreeMap::init(memory::get_stable_graph_entity_index_map_memory())
+}
+
+fn init_stable_graph_relation_index_data() -> StableBTreeMap<[u8; 32], u64, Memory> {
+    StableBTreeMap::init(memory::get_stable_graph_relation_index_map_memory())
+}
+
+fn init_stable_step_trace_data() -> StableVec<StepTrace, Memory> {
+    StableVec::init(memory::get_stable_step_trace_vec_memory())
+        .expect("call to init_stable_step_trace_data fails")
+}
+
+fn init_stable_step_trace_index_data() -> StableBTreeMap<(u64, u64), (), Memory> {
+    StableBTreeMap::init(memory::get_stable_step_trace_index_map_memory())
+}
+
 /// Initial canister balance to track the cycles usage.
 static INITIAL_CANISTER_BALANCE: AtomicU64 = AtomicU64::new(0);
 /// Canister cycles usage tracked in the periodic task.
@@ -261,6 +500,9 @@ fn create_prompt(
     agent_goal: String,
     history: Vec<ChatHistory>,
     top_lt_memory: Option<Vec<PlainDoc>>,
+    plan: Option<Vec<Subtask>>,
+    is_ask_user_enabled: bool,
+    guidance: Vec<GoalGuidance>,
 ) -> String {
     let mut tt = TinyTemplate::new();
     let template_name = "prompt";
@@ -311,7 +553,13 @@ fn create_prompt(
         agent_task: agent_task,
         agent_goal: agent_goal,
         current_date_time: current_datetime_string,
-        response_format: RESPONSE_FORMAT.to_string(),
+        current_plan: format_plan(&plan),
+        is_ask_user_enabled,
+        guidance: guidance
+            .iter()
+            .map(|guidance| format!("- {}", guidance.text))
+            .collect::<Vec<String>>()
+            .join("\n"),
         past_events: past_events.to_string(),
     };
 
@@ -321,6 +569,58 @@ fn create_prompt(
     return full_prompt;
 }
 
+fn create_memory_importance_prompt(content: String) -> String {
+    let context = MemoryImportancePromptContext {
+        memory_content: content,
+    };
+
+    let full_prompt = render_unescaped_prompt(MEMORY_IMPORTANCE_PROMPT, &context);
+    ic_cdk::println!("full_prompt: {}", full_prompt);
+
+    return full_prompt;
+}
+
+fn create_reflection_prompt(agent_goal: String, history: Vec<ChatHistory>) -> String {
+    let format_desc = format_description::parse(DATE_TIME_FORMAT).unwrap();
+
+    // truncate long tool results, the outline of the run is what matters
+    let transcript: Vec<ChatDisplayHistory> = history
+        .into_iter()
+        .map(|chat| {
+            let created_at_dt =
+                OffsetDateTime::from_unix_timestamp_nanos(chat.created_at.into()).unwrap();
+            ChatDisplayHistory {
+                content: chat
+                    .content
+                    .chars()
+                    .take(MAX_REFLECTION_MESSAGE_CHARS)
+                    .collect(),
+                role: chat.role,
+                created_at_human: created_at_dt.format(&format_desc).unwrap(),
+            }
+        })
+        .collect();
+
+    let context = ReflectionPromptContext {
+        agent_goal,
+        transcript: serde_json::to_string(&transcript).unwrap(),
+    };
+
+    let full_prompt = render_unescaped_prompt(REFLECTION_PROMPT, &context);
+    ic_cdk::println!("full_prompt: {}", full_prompt);
+
+    return full_prompt;
+}
+
+fn create_graph_extraction_prompt(source: String, content: String) -> String {
+    let context = GraphExtractionPromptContext { source, content };
+
+    let full_prompt = render_unescaped_prompt(GRAPH_EXTRACTION_PROMPT, &context);
+    ic_cdk::println!("full_prompt: {}", full_prompt);
+
+    return full_prompt;
+}
+
 fn create_web_query_prompt(query: String, content: String) -> String {
     let mut tt = TinyTemplate::new();
     let template_name = "web_query_prompt";
@@ -337,6 +637,19 @@ fn create_web_query_prompt(query: String, content: String) -> String {
     return full_prompt;
 }
 
+fn create_json_repair_prompt(error: String, malformed_json: String) -> String {
+    let context = JsonRepairPromptContext {
+        error,
+        response_format: RESPONSE_FORMAT.to_string(),
+        malformed_json,
+    };
+
+    let full_prompt = render_unescaped_prompt(JSON_REPAIR_PROMPT, &context);
+    ic_cdk::println!("full_prompt: {}", full_prompt);
+
+    return full_prompt;
+}
+
 /*
  * Chain of Thoughts Main Loop
  * @param command: Chain of Thoughts response JSON string
@@ -347,46 +660,155 @@ async fn run_chain_of_thoughts(
     goal_key: u64,
     cof_input: String,
     main_goal: String,
+) -> String {
+    return run_cof_input(num_thoughts, goal_key, CofInput::Text(cof_input), main_goal).await;
+}
+
+#[async_recursion]
+async fn run_cof_input(
+    num_thoughts: u16,
+    goal_key: u64,
+    cof_input: CofInput,
+    main_goal: String,
+) -> String {
+    let result: String =
+        run_chain_of_thoughts_step(num_thoughts, goal_key, cof_input, main_goal).await;
+
+    // the trace of a step ends when the next step begins, or here for the last step
+    finish_step_trace(goal_key);
+
+    // a child agent that stopped without completing or waiting fails with the reason it stopped
+    if is_child_goal(goal_key) && is_goal_running(goal_key) {
+        fail_child_goal(goal_key, &result);
+    }
+    // and so does the workflow run of a node goal
+    if is_goal_running(goal_key) {
+        fail_workflow_run_of_goal(goal_key, &result);
+    }
+
+    return result;
+}
+
+async fn run_chain_of_thoughts_step(
+    num_thoughts: u16,
+    goal_key: u64,
+    cof_input: CofInput,
+    main_goal: String,
 ) -> String {
     // ------ Begin Chain of Thoughts ------
+    // a child agent that failed on timeout stops at its next step
+    if is_goal_complete(goal_key) {
+        return "Goal has already completed.".to_string();
+    }
+
     let is_pause_chain_of_thoughts: bool =
         STATE.with(|state| (*state.borrow()).is_pause_chain_of_thoughts.unwrap());
     if is_pause_chain_of_thoughts {
         let message = "Chain of Thoughts is paused.".to_string();
-        insert_chat(ChatRole::System, message.clone());
+        insert_chat(goal_key, ChatRole::System, message.clone());
         return message.clone();
     }
 
-    if num_thoughts >= MAX_NUM_COF_PER_GOAL {
+    // child agents have their own, smaller thought budget
+    let max_num_thoughts: u16 = STATE
+        .with(|s| s.borrow().stable_goal_data.get(goal_key))
+        .and_then(|goal| goal.max_num_thoughts)
+        .unwrap_or(MAX_NUM_COF_PER_GOAL);
+    if num_thoughts >= max_num_thoughts {
         let message = "Chain of Thoughts has reached max number of thoughts per goal.".to_string();
-        insert_chat(ChatRole::System, message.clone());
+        insert_chat(goal_key, ChatRole::System, message.clone());
+        if is_child_goal(goal_key) {
+            save_result(goal_key, message.clone());
+            report_to_parent_goal(goal_key);
+        }
         return message.clone();
     }
 
+    // a goal out of budget completes with what it has found so far
+    if let Some(exceeded_budget) = find_goal_exceeded_budget(goal_key) {
+        let message = format!(
+            "Chain of Thoughts has run out of budget, {}.",
+            exceeded_budget
+        );
+        insert_chat(goal_key, ChatRole::System, message.clone());
+        let partial_result: String = create_partial_result(
+            &message,
+            &get_goal_plan(goal_key),
+            &get_goal_chathistory(goal_key),
+        );
+        save_result(goal_key, partial_result);
+        report_to_parent_goal(goal_key);
+        return message;
+    }
+
     if is_exceed_max_num_thoughts_allowed() {
         let message: String =
             "Chain of Thoughts has reached max number of thoughts allowed for the plan."
                 .to_string();
-        insert_chat(ChatRole::System, message.clone());
+        insert_chat(goal_key, ChatRole::System, message.clone());
         return message.clone();
     }
 
-    // parse command string
-    let cof_json = serde_json::from_str::<serde_json::Value>(&cof_input);
-    if cof_json.is_err() {
-        insert_chat(
-            ChatRole::System,
-            "ArcMind AI encountered invalid JSON response from previous command. A recovery commnand would be sent.".to_string(),
-        );
-        return run_recovery_cmd(num_thoughts, goal_key, main_goal).await;
-    }
-
-    let cof_json = cof_json.unwrap();
+    // the command called by the brain is checked as it is, command strings are parsed,
+    // repairing malformed JSON before sending a recovery command
+    let cof_json: Result<serde_json::Value, String> = match cof_input {
+        CofInput::Response(response) => {
+            // ask_user of goals without it is reported by its own command
+            validate_agent_response(&response, &create_command_schemas(true))
+                .map(|()| create_agent_command(&response))
+        }
+        CofInput::Text(cof_input) => parse_cof_input(goal_key, &cof_input).await,
+    };
+    let cof_json: serde_json::Value = match cof_json {
+        Ok(cof_json) => cof_json,
+        Err(e) => {
+            begin_step_trace(goal_key, num_thoughts, None, String::new());
+            insert_chat(
+                goal_key,
+                ChatRole::System,
+                format!("ArcMind AI encountered invalid response from previous command: {} A recovery commnand would be sent.", e),
+            );
+            return run_recovery_cmd(num_thoughts, goal_key, main_goal).await;
+        }
+    };
+    let cof_input: String = cof_json.to_string();
     let cof_cmd = cof_json["command"].clone();
     let cmd_name = cof_cmd["name"].as_str();
 
     inc_num_thoughts_processed();
 
+    begin_step_trace(
+        goal_key,
+        num_thoughts,
+        cmd_name,
+        cof_cmd["args"].to_string(),
+    );
+
+    // in dry run, commands with side effects are recorded instead of executed
+    let is_dry_run: bool = is_goal_dry_run(goal_key);
+    if is_dry_run && cmd_name.is_some_and(|name| SIDE_EFFECT_COMMANDS.contains(&name)) {
+        record_dry_run_action(
+            goal_key,
+            cmd_name.unwrap(),
+            cof_cmd["args"].to_string(),
+            "Not executed in dry run.".to_string(),
+        );
+        insert_chat(
+            goal_key,
+            ChatRole::System,
+            format!("Command {} has executed successfully.", cmd_name.unwrap()),
+        );
+
+        let next_command = create_cof_command(main_goal.to_string());
+        return run_chain_of_thoughts(
+            num_thoughts + 1,
+            goal_key,
+            next_command,
+            main_goal.to_string(),
+        )
+        .await;
+    }
+
     // match and run command
     match cmd_name {
         Some(PROMPT_CMD_START_AGENT) => {
@@ -396,13 +818,41 @@ async fn run_chain_of_thoughts(
             let prompt = cmd_args["prompt"].as_str();
 
             if name.is_none() || task.is_none() || prompt.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Recovery);
                 let sys_result =
                     format!("ArcMind AI encountered an invalid command: {}", cof_input);
-                insert_chat(ChatRole::System, sys_result.to_string());
+                insert_chat(goal_key, ChatRole::System, sys_result.to_string());
 
                 let user_result =
                     "The command you provided is invalid. Use a valid command and try again.";
-                insert_chat(ChatRole::User, user_result.to_string());
+                insert_chat(goal_key, ChatRole::User, user_result.to_string());
+
+                let next_command = create_cof_command(main_goal.to_string());
+                return run_chain_of_thoughts(
+                    num_thoughts + 1,
+                    goal_key,
+                    next_command,
+                    main_goal.to_string(),
+                )
+                .await;
+            }
+
+            // any other agent name delegates the prompt to a child agent
+            if name.unwrap() != TOP_CMD_AGENT_NAME {
+                let result: String = match start_child_agent(
+                    goal_key,
+                    name.unwrap().to_string(),
+                    task.unwrap().to_string(),
+                    prompt.unwrap().to_string(),
+                ) {
+                    Ok(child_goal_key) => format!(
+                        "Agent {} has started with agent id {}. Use wait_for_agents to wait for its result.",
+                        name.unwrap(),
+                        child_goal_key
+                    ),
+                    Err(e) => e,
+                };
+                insert_chat(goal_key, ChatRole::System, result);
 
                 let next_command = create_cof_command(main_goal.to_string());
                 return run_chain_of_thoughts(
@@ -414,36 +864,134 @@ async fn run_chain_of_thoughts(
                 .await;
             }
 
+            // child agents are prompted with their own name and task
+            let goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(goal_key));
+            let agent_name: String = goal
+                .as_ref()
+                .and_then(|goal| goal.agent_name.clone())
+                .unwrap_or(name.unwrap().to_string());
+            let agent_task: String = goal
+                .as_ref()
+                .and_then(|goal| goal.agent_task.clone())
+                .unwrap_or(task.unwrap().to_string());
+            let is_ask_user_enabled: bool = goal
+                .as_ref()
+                .and_then(|goal| goal.is_ask_user_enabled)
+                .unwrap_or(false);
+            let is_debug_mode: bool = goal
+                .as_ref()
+                .and_then(|goal| goal.is_debug_mode)
+                .unwrap_or(false);
+
             // get the first chatdisplayhistory from recent_display_history
-            let recent_display_history = get_chathistory();
+            let recent_display_history = get_goal_chathistory(goal_key);
             let first_chat_display_history = recent_display_history.first().unwrap();
 
             // generate embeddings using first_chat_display_history.content
             let embeddings: Embeddings =
-                generate_embeddings(first_chat_display_history.content.clone())
+                generate_embeddings(Some(goal_key), first_chat_display_history.content.clone())
                     .await
                     .unwrap();
 
+            // lessons learned from past goals come first
+            let lessons: Vec<PlainDoc> = search_lessons(embeddings.clone()).await;
+            let lesson_ids: Vec<u64> = lessons.iter().filter_map(|doc| doc.id).collect();
+
             // load relevant long term memory from vector_db canister
-            let top_lt_memory: Option<Vec<PlainDoc>> = search_vecdoc(embeddings).await;
+            let top_lt_memory: Option<Vec<PlainDoc>> =
+                search_vecdoc(first_chat_display_history.content.clone(), embeddings).await;
+            let top_lt_memory: Vec<PlainDoc> = top_lt_memory
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|doc| !doc.id.is_some_and(|id| lesson_ids.contains(&id)))
+                .collect();
+
+            // rank by similarity, recency and importance
+            let retrieval_weights: RetrievalWeights = STATE
+                .with(|state| (*state.borrow()).retrieval_weights.clone())
+                .unwrap_or_else(default_retrieval_weights);
+            let top_lt_memory: Vec<PlainDoc> = rank_memories(
+                top_lt_memory,
+                &retrieval_weights,
+                time(),
+                VEC_SEARCH_TOP_K_NN,
+            );
+            let mut accessed_ids: Vec<u64> =
+                top_lt_memory.iter().filter_map(|doc| doc.id).collect();
+            accessed_ids.extend(lesson_ids);
+            touch_vecdocs(accessed_ids).await;
+
+            let top_lt_memory: Vec<PlainDoc> = expand_chunk_neighbours(top_lt_memory).await;
+
+            // pinned memories are always included ahead of the search results
+            let mut lt_memory: Vec<PlainDoc> = lessons;
+            lt_memory.extend(get_pinned_vecdocs().await);
+            lt_memory.extend(top_lt_memory);
 
             // create full prompt
             let full_prompt = create_prompt(
-                name.unwrap().to_string(),
-                task.unwrap().to_string(),
+                agent_name,
+                agent_task,
                 prompt.unwrap().to_string(),
                 recent_display_history,
-                top_lt_memory,
+                Some(lt_memory),
+                get_goal_plan(goal_key),
+                is_ask_user_enabled,
+                take_goal_guidance(goal_key),
             );
 
             // insert result into chat history
-            let result: String = start_agent(full_prompt, None).await;
-            insert_chat(ChatRole::ArcMind, result.clone());
+            let route: ModelRoute = get_model_route(Some(goal_key), StepKind::Main);
+            let response: Result<AgentResponse, String> = match think(
+                Some(goal_key),
+                &route,
+                full_prompt.clone(),
+                create_command_schemas(is_ask_user_enabled),
+            )
+            .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    set_step_outcome(goal_key, StepOutcome::Error);
+                    let message = format!("ArcMind AI could not decide the next command: {}", e);
+                    insert_chat(goal_key, ChatRole::System, message.clone());
+                    return message;
+                }
+            };
+            let result: String = match &response {
+                Ok(response) => format_agent_response(response),
+                Err(answer) => answer.clone(),
+            };
+            insert_chat(goal_key, ChatRole::ArcMind, result.clone());
+            trace_step_result(
+                goal_key,
+                result.len(),
+                Some(route.model.unwrap_or(BRAIN_DEFAULT_MODEL.to_string())),
+            );
 
-            return run_chain_of_thoughts(
+            // an answer without a command is never parsed as one, a recovery command is sent instead
+            let response: AgentResponse = match response {
+                Ok(response) => response,
+                Err(_) => {
+                    insert_chat(
+                        goal_key,
+                        ChatRole::System,
+                        "ArcMind AI answered without calling a command. A recovery command would be sent.".to_string(),
+                    );
+                    return run_recovery_cmd(num_thoughts, goal_key, main_goal).await;
+                }
+            };
+
+            // in debug mode the owner reviews each decision before it runs
+            if is_debug_mode {
+                wait_for_debug_step(goal_key, num_thoughts + 1, full_prompt, result);
+                return "Waiting for the owner to step through the next command.".to_string();
+            }
+
+            return run_cof_input(
                 num_thoughts + 1,
                 goal_key,
-                result,
+                CofInput::Response(response),
                 main_goal.to_string(),
             )
             .await;
@@ -452,22 +1000,40 @@ async fn run_chain_of_thoughts(
             let cmd_args = cof_cmd["args"].clone();
             let query = cmd_args["query"].as_str();
             if query.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Error);
                 return "Invalid google command.".to_string();
             }
 
-            let result: String = google(query.unwrap().to_string()).await;
+            let result: String = if is_dry_run {
+                get_dry_run_output(goal_key, PROMPT_CMD_GOOGLE, query.unwrap())
+            } else {
+                google(Some(goal_key), query.unwrap().to_string()).await
+            };
 
             // insert result into chat history
-            insert_chat(ChatRole::System, result.clone());
+            insert_chat(goal_key, ChatRole::System, result.clone());
+            trace_step_result(goal_key, result.len(), None);
 
             let google_cmd_history = "Command google returned: Result saved successfully.";
-            insert_chat(ChatRole::System, google_cmd_history.to_string());
-
-            // generate embeddings
-            let embeddings: Embeddings = generate_embeddings(result.clone()).await.unwrap();
+            insert_chat(goal_key, ChatRole::System, google_cmd_history.to_string());
+
+            // dry runs leave long term memory untouched
+            if !is_dry_run {
+                // chunk, generate embeddings and save them to vectordb
+                if let Err(e) =
+                    add_chunked_vecdocs(Some(goal_key), result.clone(), PROMPT_CMD_GOOGLE).await
+                {
+                    insert_chat(
+                        goal_key,
+                        ChatRole::System,
+                        format!("The result could not be saved into long term memory: {}", e),
+                    );
+                }
 
-            // save embeddings to vectordb
-            add_vecdoc(result.clone(), embeddings).await;
+                // save entities and relations into knowledge graph
+                let source = format!("{}: {}", PROMPT_CMD_GOOGLE, query.unwrap());
+                extract_graph_knowledge(Some(goal_key), result.clone(), source).await;
+            }
 
             let next_command = create_cof_command(main_goal.to_string());
             return run_chain_of_thoughts(
@@ -483,32 +1049,61 @@ async fn run_chain_of_thoughts(
             let url = cmd_args["url"].as_str();
             let question: Option<&str> = cmd_args["question"].as_str();
             if url.is_none() || question.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Error);
                 return "Invalid browse_website command.".to_string();
             }
 
-            let web_page_content: String =
-                browse_website(url.unwrap().to_string(), question.unwrap().to_string()).await;
+            let web_page_content: String = if is_dry_run {
+                get_dry_run_output(goal_key, PROMPT_CMD_BROWSE_WEBSITE, url.unwrap())
+            } else {
+                browse_website(
+                    Some(goal_key),
+                    url.unwrap().to_string(),
+                    question.unwrap().to_string(),
+                )
+                .await
+            };
+
+            let web_page_content_size: usize = web_page_content.len();
 
             // create web query prompt
             let web_query_prompt =
                 create_web_query_prompt(question.unwrap().to_string(), web_page_content);
 
-            // extract browse_website_gpt_model from state
-            let gpt_model: Option<String> =
-                STATE.with(|state| (*state.borrow()).browse_website_gpt_model.clone());
-
-            let result: String = start_agent(web_query_prompt, gpt_model).await;
-            insert_chat(ChatRole::System, result.clone());
+            let route: ModelRoute = get_model_route(Some(goal_key), StepKind::WebExtraction);
+            let result: String = start_agent(Some(goal_key), &route, web_query_prompt).await;
+            insert_chat(goal_key, ChatRole::System, result.clone());
+            trace_step_result(
+                goal_key,
+                web_page_content_size,
+                Some(route.model.unwrap_or(BRAIN_DEFAULT_MODEL.to_string())),
+            );
 
             let browse_website_cmd_history =
                 "Command browse_website returned -> Result saved successfully.";
-            insert_chat(ChatRole::System, browse_website_cmd_history.to_string());
+            insert_chat(
+                goal_key,
+                ChatRole::System,
+                browse_website_cmd_history.to_string(),
+            );
 
-            // generate embeddings
-            let embeddings: Embeddings = generate_embeddings(result.clone()).await.unwrap();
+            if !is_dry_run {
+                // chunk, generate embeddings and save them to vectordb
+                if let Err(e) =
+                    add_chunked_vecdocs(Some(goal_key), result.clone(), PROMPT_CMD_BROWSE_WEBSITE)
+                        .await
+                {
+                    insert_chat(
+                        goal_key,
+                        ChatRole::System,
+                        format!("The result could not be saved into long term memory: {}", e),
+                    );
+                }
 
-            // save embeddings to vectordb
-            add_vecdoc(result.clone(), embeddings).await;
+                // save entities and relations into knowledge graph
+                extract_graph_knowledge(Some(goal_key), result.clone(), url.unwrap().to_string())
+                    .await;
+            }
 
             let next_command = create_cof_command(main_goal.to_string());
             return run_chain_of_thoughts(
@@ -524,38 +1119,53 @@ async fn run_chain_of_thoughts(
             let key = cmd_args["key"].as_str();
             let text = cmd_args["text"].as_str();
             if text.is_none() || key.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Error);
                 return "Invalid write_file_and_shutdown command.".to_string();
             }
 
-            write_file_and_shutdown(key.unwrap().to_string(), text.unwrap().to_string());
+            write_file_and_shutdown(
+                goal_key,
+                key.unwrap().to_string(),
+                text.unwrap().to_string(),
+            );
+            save_result(goal_key, text.unwrap().to_string());
+            report_to_parent_goal(goal_key);
 
             let write_cmd_history = "Command write_file_and_shutdown has run successfully.";
-            insert_chat(ChatRole::System, write_cmd_history.to_string());
+            insert_chat(goal_key, ChatRole::System, write_cmd_history.to_string());
 
             // insert shutdown result into chat history
             let shutdown_result =
                 "ArcMind AI has completed the goal. End of processing.".to_string();
-            insert_chat(ChatRole::System, shutdown_result.to_string());
+            insert_chat(goal_key, ChatRole::System, shutdown_result.to_string());
+
+            // store lessons learned for future goals
+            reflect_on_goal(goal_key).await;
 
             return cof_input;
         }
         Some(PROMPT_CMD_DO_NOTHING) => {
             // insert result into chat history
             let result = "ArcMind AI has decided to do nothing. End of processing.".to_string();
-            insert_chat(ChatRole::System, result.to_string());
+            insert_chat(goal_key, ChatRole::System, result.to_string());
             // save result
             save_result(goal_key, result.clone());
+            report_to_parent_goal(goal_key);
 
             return result;
         }
         Some(PROMPT_CMD_SHUTDOWN) => {
             // save result
             save_result(goal_key, cof_input.clone());
+            report_to_parent_goal(goal_key);
 
             // insert shutdown result into chat history
             let shutdown_result =
                 "ArcMind AI has completed the goal. End of processing.".to_string();
-            insert_chat(ChatRole::System, shutdown_result.to_string());
+            insert_chat(goal_key, ChatRole::System, shutdown_result.to_string());
+
+            // store lessons learned for future goals
+            reflect_on_goal(goal_key).await;
 
             return cof_input;
         }
@@ -566,6 +1176,7 @@ async fn run_chain_of_thoughts(
             let recipient_principa_id = cmd_args["recipient_principal_id"].as_str();
 
             if amount.is_none() || token_type.is_none() || recipient_principa_id.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Error);
                 return "Invalid beanfi stream command.".to_string();
             }
 
@@ -586,11 +1197,13 @@ async fn run_chain_of_thoughts(
             ic_cdk::println!("BeamFi streaming escrow_id {}", escrow_id);
 
             insert_chat(
+                goal_key,
                 ChatRole::System,
                 "Command beamfi_stream_payment has executed successfully.".to_string(),
             );
 
             insert_chat(
+                goal_key,
                 ChatRole::System,
                 "Please move on to the next command. If none is left, please shutdown.".to_string(),
             );
@@ -604,247 +1217,3149 @@ async fn run_chain_of_thoughts(
             )
             .await;
         }
-        Some(n) => {
+        Some(PROMPT_CMD_GRAPH_QUERY) => {
+            let cmd_args = cof_cmd["args"].clone();
+            let entity = cmd_args["entity"].as_str();
+            if entity.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Error);
+                return "Invalid graph_query command.".to_string();
+            }
+
+            let result: String = match query_graph_entity(entity.unwrap()) {
+                Some(graph_query_result) => serde_json::to_string(&graph_query_result).unwrap(),
+                None => format!("Nothing is known about {} yet.", entity.unwrap()),
+            };
+            insert_chat(goal_key, ChatRole::System, result);
+
+            let graph_query_cmd_history =
+                "Command graph_query returned: Result saved successfully.";
             insert_chat(
+                goal_key,
                 ChatRole::System,
-                format!("ArcMind AI encountered an invalid command: {}", n),
+                graph_query_cmd_history.to_string(),
             );
-            return run_recovery_cmd(num_thoughts, goal_key, main_goal).await;
+
+            let next_command = create_cof_command(main_goal.to_string());
+            return run_chain_of_thoughts(
+                num_thoughts + 1,
+                goal_key,
+                next_command,
+                main_goal.to_string(),
+            )
+            .await;
         }
-        None => {
-            insert_chat(
-                ChatRole::System,
-                "ArcMind AI encountered None command".to_string(),
-            );
-            return run_recovery_cmd(num_thoughts, goal_key, main_goal).await;
+        Some(PROMPT_CMD_SET_PLAN) => {
+            let cmd_args = cof_cmd["args"].clone();
+            let subtasks = parse_subtasks(&cmd_args["subtasks"]);
+            if subtasks.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Error);
+                return "Invalid set_plan command.".to_string();
+            }
+
+            let result = format!("Plan is set:\n{}", format_plan(&subtasks));
+            update_goal_plan(goal_key, subtasks);
+            insert_chat(goal_key, ChatRole::System, result);
+
+            let next_command = create_cof_command(main_goal.to_string());
+            return run_chain_of_thoughts(
+                num_thoughts + 1,
+                goal_key,
+                next_command,
+                main_goal.to_string(),
+            )
+            .await;
         }
-    }
+        Some(PROMPT_CMD_UPDATE_SUBTASK) => {
+            let cmd_args = cof_cmd["args"].clone();
+            // accept the subtask number either as a string or a number
+            let index: Option<usize> = match &cmd_args["index"] {
+                serde_json::Value::Number(n) => n.as_u64().map(|n| n as usize),
+                serde_json::Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            };
+            let status = cmd_args["status"].as_str().and_then(parse_subtask_status);
+            if index.is_none() || status.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Error);
+                return "Invalid update_subtask command.".to_string();
+            }
 
-    // ------ End of Chain of Thoughts ------
-}
+            let mut plan: Vec<Subtask> = get_goal_plan(goal_key).unwrap_or_default();
+            let result = match plan.get_mut(index.unwrap().wrapping_sub(1)) {
+                Some(subtask) => {
+                    subtask.status = status.unwrap();
+                    let plan = Some(plan);
+                    let result = format!("Plan is updated:\n{}", format_plan(&plan));
+                    update_goal_plan(goal_key, plan);
+                    result
+                }
+                None => format!(
+                    "Subtask {} does not exist in the plan. Use set_plan to create the plan first.",
+                    index.unwrap()
+                ),
+            };
+            insert_chat(goal_key, ChatRole::System, result);
 
-fn inc_num_thoughts_processed() {
-    STATE.with(|state| {
-        let cur_state = state.borrow().num_thoughts_processed;
-        state.borrow_mut().num_thoughts_processed = cur_state + 1;
-    });
-}
+            let next_command = create_cof_command(main_goal.to_string());
+            return run_chain_of_thoughts(
+                num_thoughts + 1,
+                goal_key,
+                next_command,
+                main_goal.to_string(),
+            )
+            .await;
+        }
+        Some(PROMPT_CMD_WAIT_FOR_AGENTS) => {
+            let cmd_args = cof_cmd["args"].clone();
+            let child_goal_keys: Vec<u64> = get_child_goal_keys(goal_key);
+
+            // wait for the given agents, or for all agents started by this goal
+            let agent_ids: Vec<u64> = match cmd_args["agent_ids"].as_array() {
+                Some(ids) => ids
+                    .iter()
+                    .filter_map(parse_goal_key)
+                    .filter(|id| child_goal_keys.contains(id))
+                    .collect(),
+                None => child_goal_keys,
+            };
+            if agent_ids.is_empty() {
+                set_step_outcome(goal_key, StepOutcome::Error);
+                return "Invalid wait_for_agents command.".to_string();
+            }
 
-async fn run_recovery_cmd(num_thoughts: u16, goal_key: u64, main_goal: String) -> String {
-    let user_result = "The command you provided is invalid. Use a valid command and try again.";
-    insert_chat(ChatRole::User, user_result.to_string());
+            let running_ids: Vec<u64> = agent_ids
+                .into_iter()
+                .filter(|id| !is_goal_complete(*id))
+                .collect();
+            if !running_ids.is_empty() {
+                wait_for_child_goals(goal_key, num_thoughts, running_ids.clone());
 
-    let next_command = create_cof_command(main_goal.to_string());
-    return run_chain_of_thoughts(
-        num_thoughts + 1,
-        goal_key,
-        next_command,
+                let result = format!("Waiting for agents {:?} to finish.", running_ids);
+                insert_chat(goal_key, ChatRole::System, result.clone());
+                return result;
+            }
+
+            insert_chat(
+                goal_key,
+                ChatRole::System,
+                "Command wait_for_agents returned: All agents have finished.".to_string(),
+            );
+
+            let next_command = create_cof_command(main_goal.to_string());
+            return run_chain_of_thoughts(
+                num_thoughts + 1,
+                goal_key,
+                next_command,
+                main_goal.to_string(),
+            )
+            .await;
+        }
+        Some(PROMPT_CMD_ASK_USER) => {
+            let cmd_args = cof_cmd["args"].clone();
+            let question = cmd_args["question"].as_str();
+            if question.is_none() {
+                set_step_outcome(goal_key, StepOutcome::Error);
+                return "Invalid ask_user command.".to_string();
+            }
+
+            let is_ask_user_enabled: bool = STATE
+                .with(|s| s.borrow().stable_goal_data.get(goal_key))
+                .and_then(|goal| goal.is_ask_user_enabled)
+                .unwrap_or(false);
+            if is_ask_user_enabled {
+                wait_for_user_reply(goal_key, num_thoughts, question.unwrap().to_string());
+
+                let result = format!("Waiting for the user to answer: {}", question.unwrap());
+                insert_chat(goal_key, ChatRole::System, result.clone());
+                return result;
+            }
+
+            insert_chat(
+                goal_key,
+                ChatRole::System,
+                "Command ask_user is not available for this goal. Decide on your own and move on."
+                    .to_string(),
+            );
+
+            let next_command = create_cof_command(main_goal.to_string());
+            return run_chain_of_thoughts(
+                num_thoughts + 1,
+                goal_key,
+                next_command,
+                main_goal.to_string(),
+            )
+            .await;
+        }
+        Some(n) => {
+            insert_chat(
+                goal_key,
+                ChatRole::System,
+                format!("ArcMind AI encountered an invalid command: {}", n),
+            );
+            return run_recovery_cmd(num_thoughts, goal_key, main_goal).await;
+        }
+        None => {
+            insert_chat(
+                goal_key,
+                ChatRole::System,
+                "ArcMind AI encountered None command".to_string(),
+            );
+            return run_recovery_cmd(num_thoughts, goal_key, main_goal).await;
+        }
+    }
+
+    // ------ End of Chain of Thoughts ------
+}
+
+fn inc_num_thoughts_processed() {
+    STATE.with(|state| {
+        let cur_state = state.borrow().num_thoughts_processed;
+        state.borrow_mut().num_thoughts_processed = cur_state + 1;
+    });
+}
+
+/*
+ * Parses the command of a step. Malformed JSON is extracted and repaired locally first,
+ * then by a "fix this JSON" call to the brain as the last resort. The repair call is only
+ * made for input with JSON in it, and counts as a thought and against the goal budget.
+ * Valid JSON with invalid args is never repaired, as the repair would make the args up.
+ */
+async fn parse_cof_input(goal_key: u64, cof_input: &str) -> Result<serde_json::Value, String> {
+    // ask_user of goals without it is reported by its own command
+    let commands: Vec<CommandSchema> = create_command_schemas(true);
+
+    let error: String = match parse_json_object(cof_input) {
+        Ok(cof_json) => {
+            validate_command(&cof_json, &commands)?;
+            return Ok(cof_json);
+        }
+        Err(e) => e,
+    };
+
+    // free text has nothing to repair
+    if !cof_input.contains('{') {
+        return Err(error);
+    }
+
+    // the step itself is counted as a thought after parsing, so the repair needs one more
+    if get_num_thoughts_processed() >= get_max_num_thoughts_allowed() {
+        return Err(error);
+    }
+    inc_num_thoughts_processed();
+
+    let repair_prompt = create_json_repair_prompt(error, cof_input.to_string());
+    let route: ModelRoute = get_model_route(Some(goal_key), StepKind::JsonRepair);
+    let repaired_input: String = try_start_agent(Some(goal_key), &route, repair_prompt).await?;
+
+    // the usage of the repair is recorded to the goal, a repaired command is not run over budget
+    if let Some(exceeded_budget) = find_goal_exceeded_budget(goal_key) {
+        return Err(format!(
+            "The JSON repair has run out of budget, {}.",
+            exceeded_budget
+        ));
+    }
+
+    let cof_json: serde_json::Value = parse_json_object(&repaired_input)?;
+    validate_command(&cof_json, &commands)?;
+
+    insert_chat(
+        goal_key,
+        ChatRole::System,
+        "ArcMind AI repaired the invalid JSON response from previous command.".to_string(),
+    );
+
+    return Ok(cof_json);
+}
+
+async fn run_recovery_cmd(num_thoughts: u16, goal_key: u64, main_goal: String) -> String {
+    set_step_outcome(goal_key, StepOutcome::Recovery);
+
+    let user_result = "The command you provided is invalid. Use a valid command and try again.";
+    insert_chat(goal_key, ChatRole::User, user_result.to_string());
+
+    let next_command = create_cof_command(main_goal.to_string());
+    return run_chain_of_thoughts(
+        num_thoughts + 1,
+        goal_key,
+        next_command,
         main_goal.to_string(),
     )
     .await;
 }
 
-async fn start_agent(question: String, gpt_model: Option<String>) -> String {
+async fn start_agent(goal_key: Option<u64>, route: &ModelRoute, question: String) -> String {
+    return try_start_agent(goal_key, route, question)
+        .await
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+}
+
+async fn try_start_agent(
+    goal_key: Option<u64>,
+    route: &ModelRoute,
+    question: String,
+) -> Result<String, String> {
     let brain_canister: Principal = STATE.with(|state| (*state.borrow()).brain_canister.unwrap());
     let num_retries: i8 = 0;
-    let (result,): (String,) =
-        ic_cdk::api::call::call(brain_canister, "ask", (question, gpt_model, num_retries))
-            .await
-            .expect("call to ask failed");
+    let request_id: Option<String> = None;
+    let (result,): (AskResult,) = ic_cdk::api::call::call(
+        brain_canister,
+        "ask_with_usage",
+        (
+            question,
+            route.model.clone(),
+            num_retries,
+            request_id,
+            route.temperature,
+        ),
+    )
+    .await
+    .map_err(|(code, message)| format!("call to ask_with_usage failed: {:?} {}", code, message))?;
+
+    record_usage(goal_key, CostSource::Brain, &result.usage);
+
+    return Ok(result.content);
+}
+
+// Asks the brain to call one of the commands. The Err is a failed call, the inner Err is the
+// answer of the model when no command was called.
+async fn think(
+    goal_key: Option<u64>,
+    route: &ModelRoute,
+    question: String,
+    commands: Vec<CommandSchema>,
+) -> Result<Result<AgentResponse, String>, String> {
+    let brain_canister: Principal = STATE.with(|state| (*state.borrow()).brain_canister.unwrap());
+    let num_retries: i8 = 0;
+    let request_id: Option<String> = None;
+    let (result,): (ThinkResult,) = ic_cdk::api::call::call(
+        brain_canister,
+        "ask_with_commands",
+        (
+            question,
+            commands,
+            route.model.clone(),
+            num_retries,
+            request_id,
+            route.temperature,
+        ),
+    )
+    .await
+    .map_err(|(code, message)| {
+        format!("call to ask_with_commands failed: {:?} {}", code, message)
+    })?;
+
+    record_usage(goal_key, CostSource::Brain, &result.usage);
+
+    return Ok(result.response);
+}
+
+async fn add_vecdoc(vec_doc: VecDoc) -> Result<String, String> {
+    let vector_canister: Principal = STATE.with(|state| (*state.borrow()).vector_canister.unwrap());
+
+    let (result,): (String,) = ic_cdk::api::call::call(vector_canister, "add", (vec_doc,))
+        .await
+        .map_err(|(code, message)| {
+            format!("call to vector_canister.add failed: {:?} {}", code, message)
+        })?;
+
+    return Ok(result);
+}
+
+// Splits content into chunks, generates embeddings and saves each chunk into vectordb
+// Returns the parent document id shared by all chunks
+async fn add_chunked_vecdocs(
+    goal_key: Option<u64>,
+    content: String,
+    namespace: &str,
+) -> Result<String, String> {
+    let now: Timestamp = time();
+    let parent_id = format!("{}-{}", namespace, now);
+    let chunks: Vec<Chunk> = chunk_content(&content, CHUNK_MAX_TOKENS, CHUNK_OVERLAP_TOKENS);
+
+    let mut chunk_embeddings: Vec<(Chunk, Embeddings)> = Vec::new();
+    for chunk in chunks {
+        let embeddings: Embeddings = generate_embeddings(goal_key, chunk.content.clone()).await?;
+        chunk_embeddings.push((chunk, embeddings));
+    }
+
+    // skip chunks that are near-duplicates of existing memories, refreshing those instead.
+    // The searches of all chunks are made at once instead of one after another.
+    let duplicates: Vec<Option<PlainDoc>> = join_all(
+        chunk_embeddings
+            .iter()
+            .map(|(_, embeddings)| find_duplicate_vecdoc(embeddings.clone())),
+    )
+    .await;
+    let mut new_chunks: Vec<(Chunk, Embeddings)> = Vec::new();
+    let mut duplicate_ids: Vec<u64> = Vec::new();
+    for ((chunk, embeddings), duplicate) in chunk_embeddings.into_iter().zip(duplicates) {
+        match duplicate {
+            Some(duplicate) => duplicate_ids.extend(duplicate.id),
+            None => new_chunks.push((chunk, embeddings)),
+        }
+    }
+
+    ic_cdk::println!(
+        "Skipped {} near-duplicate chunks of {}",
+        duplicate_ids.len(),
+        parent_id
+    );
+    touch_vecdocs(duplicate_ids).await;
+
+    if new_chunks.is_empty() {
+        return Ok(parent_id);
+    }
+
+    let importance: f32 = score_memory_importance(goal_key, &content).await;
+    for (chunk, embeddings) in new_chunks {
+        let vec_doc = VecDoc {
+            content: chunk.content,
+            embeddings,
+            namespace: Some(namespace.to_string()),
+            parent_id: Some(parent_id.clone()),
+            chunk_index: Some(chunk.index),
+            importance: Some(importance),
+        };
+        add_vecdoc(vec_doc).await?;
+    }
+
+    return Ok(parent_id);
+}
+
+// Replaces each chunk found by search with the chunk and its neighbours of the same document.
+// Chunks are kept as they are when the neighbours cannot be retrieved.
+async fn expand_chunk_neighbours(docs: Vec<PlainDoc>) -> Vec<PlainDoc> {
+    let mut expanded_docs: Vec<PlainDoc> = Vec::new();
+    let mut covered_chunks: Vec<(String, u32)> = Vec::new();
+
+    for doc in docs {
+        let (parent_id, chunk_index) = match (doc.parent_id.clone(), doc.chunk_index) {
+            (Some(parent_id), Some(chunk_index)) => (parent_id, chunk_index),
+            _ => {
+                expanded_docs.push(doc);
+                continue;
+            }
+        };
+
+        // skip chunks already included as neighbours of a previous result
+        if covered_chunks.contains(&(parent_id.clone(), chunk_index)) {
+            continue;
+        }
+
+        let from_index = chunk_index.saturating_sub(1);
+        let to_index = chunk_index + 1;
+        let mut chunks: Vec<PlainDoc> =
+            match call_vector_memory_api("get_chunks", (parent_id.clone(), from_index, to_index))
+                .await
+            {
+                Ok((chunks,)) => chunks,
+                Err(e) => {
+                    ic_cdk::println!("{}", e);
+                    expanded_docs.push(doc);
+                    continue;
+                }
+            };
+
+        chunks.sort_by_key(|chunk| chunk.chunk_index);
+        for chunk in chunks.iter() {
+            covered_chunks.push((parent_id.clone(), chunk.chunk_index.unwrap_or(chunk_index)));
+        }
+
+        let content = join_chunks(
+            &chunks
+                .into_iter()
+                .map(|chunk| chunk.content)
+                .collect::<Vec<String>>(),
+        );
+
+        expanded_docs.push(PlainDoc { content, ..doc });
+    }
+
+    return expanded_docs;
+}
+
+// Searches long term memory by embeddings, re-scored with the keyword matches of the text
+// if hybrid search weights are configured
+async fn search_vecdoc(text: String, embeddings: Embeddings) -> Option<Vec<PlainDoc>> {
+    let hybrid_search_weights: Option<HybridSearchWeights> =
+        STATE.with(|state| (*state.borrow()).hybrid_search_weights.clone());
+    let num_candidates: usize = match hybrid_search_weights {
+        Some(_) => VEC_SEARCH_NUM_HYBRID_CANDIDATES,
+        None => VEC_SEARCH_NUM_CANDIDATES,
+    };
+    let query: VecQuery = VecQuery::Embeddings(embeddings);
+    let vector_canister: Principal = STATE.with(|state| (*state.borrow()).vector_canister.unwrap());
+
+    let result: Result<(Option<Vec<PlainDoc>>,), _> =
+        ic_cdk::api::call::call(vector_canister, "search", (query, num_candidates)).await;
+    let docs: Option<Vec<PlainDoc>> = match result {
+        Ok((docs,)) => docs,
+        Err((code, message)) => {
+            ic_cdk::println!(
+                "call to vector_canister.search failed: {:?} {}",
+                code,
+                message
+            );
+            return None;
+        }
+    };
+
+    return match hybrid_search_weights {
+        Some(weights) => docs.map(|docs| apply_hybrid_scores(&text, docs, &weights)),
+        None => docs,
+    };
+}
+
+// Reflects on the transcript of a finished goal and saves the lessons learned as long term memory
+async fn reflect_on_goal(goal_key: u64) {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return,
+    };
+
+    // child agents report back to their parent, which reflects on the whole goal
+    if goal.parent_goal_key.is_some() {
+        return;
+    }
+    // lessons of a simulated goal are not kept
+    if goal.is_dry_run.unwrap_or(false) {
+        return;
+    }
+
+    let transcript: Vec<ChatHistory> = get_goal_chathistory(goal_key)
+        .into_iter()
+        .filter(|chat| chat.created_at >= goal.created_at)
+        .collect();
+
+    let reflection_prompt = create_reflection_prompt(goal.goal.clone(), transcript);
+    let reflection: String = match try_start_agent(
+        Some(goal_key),
+        &get_model_route(Some(goal_key), StepKind::Reflection),
+        reflection_prompt,
+    )
+    .await
+    {
+        Ok(reflection) => reflection,
+        Err(e) => {
+            ic_cdk::println!("Failed to reflect on goal: {}", e);
+            return;
+        }
+    };
+    let lesson = format!(
+        "Lessons learned from goal \"{}\":\n{}",
+        goal.goal, reflection
+    );
+
+    let embeddings: Embeddings = match generate_embeddings(Some(goal_key), lesson.clone()).await {
+        Ok(embeddings) => embeddings,
+        Err(e) => {
+            ic_cdk::println!("Failed to save lessons learned: {}", e);
+            return;
+        }
+    };
+
+    let vec_doc = VecDoc {
+        content: lesson,
+        embeddings,
+        namespace: Some(VEC_NAMESPACE_LESSON.to_string()),
+        parent_id: None,
+        chunk_index: None,
+        importance: Some(LESSON_IMPORTANCE),
+    };
+    if let Err(e) = add_vecdoc(vec_doc).await {
+        ic_cdk::println!("Failed to save lessons learned: {}", e);
+    }
+}
+
+// No lessons are recalled when the vector canister memory API is unavailable
+async fn search_lessons(embeddings: Embeddings) -> Vec<PlainDoc> {
+    let query: VecQuery = VecQuery::Embeddings(embeddings);
+
+    let result: Result<(Option<Vec<PlainDoc>>,), String> = call_vector_memory_api(
+        "search_namespace",
+        (query, VEC_NAMESPACE_LESSON, VEC_SEARCH_TOP_K_LESSONS),
+    )
+    .await;
+
+    return match result {
+        Ok((docs,)) => docs.unwrap_or_default(),
+        Err(e) => {
+            ic_cdk::println!("{}", e);
+            Vec::new()
+        }
+    };
+}
+
+// Asks the LLM to extract entities and relations from a tool result into the knowledge graph.
+// Nothing is saved when the call fails.
+async fn extract_graph_knowledge(goal_key: Option<u64>, content: String, source: String) {
+    let extraction_prompt = create_graph_extraction_prompt(source.clone(), content);
+    let answer: String = match try_start_agent(
+        goal_key,
+        &get_model_route(goal_key, StepKind::Summarisation),
+        extraction_prompt,
+    )
+    .await
+    {
+        Ok(answer) => answer,
+        Err(e) => {
+            ic_cdk::println!("Failed to extract knowledge graph: {}", e);
+            return;
+        }
+    };
+
+    upsert_graph_extraction(parse_graph_extraction(&answer), &source);
+}
+
+// Asks the LLM to rate how important the content is to remember, from 0.0 to 1.0.
+// A failed call leaves the memory with the default importance.
+async fn score_memory_importance(goal_key: Option<u64>, content: &str) -> f32 {
+    let truncated_content: String = content.chars().take(MAX_IMPORTANCE_PROMPT_CHARS).collect();
+    let importance_prompt = create_memory_importance_prompt(truncated_content);
+    let result: Result<String, String> = try_start_agent(
+        goal_key,
+        &get_model_route(goal_key, StepKind::ImportanceScoring),
+        importance_prompt,
+    )
+    .await;
+
+    return match result {
+        Ok(answer) => parse_importance(&answer),
+        Err(e) => {
+            ic_cdk::println!("Failed to score memory importance: {}", e);
+            DEFAULT_MEMORY_IMPORTANCE
+        }
+    };
+}
+
+// Updates last accessed time and access count of memories used in a prompt.
+// Access stats are best effort and are left as they are when the memory API is unavailable.
+async fn touch_vecdocs(ids: Vec<u64>) {
+    if ids.is_empty() {
+        return;
+    }
+
+    let result: Result<(), String> = call_vector_memory_api("touch", (ids, time())).await;
+    if let Err(e) = result {
+        ic_cdk::println!("{}", e);
+    }
+}
+
+// No memories are pinned when the vector canister memory API is unavailable
+async fn get_pinned_vecdocs() -> Vec<PlainDoc> {
+    return match call_vector_memory_api("get_pinned", ()).await {
+        Ok((result,)) => result,
+        Err(e) => {
+            ic_cdk::println!("{}", e);
+            Vec::new()
+        }
+    };
+}
+
+// Returns the most similar existing memory if it is a near-duplicate of the embeddings.
+// The content is treated as new when the search fails.
+async fn find_duplicate_vecdoc(embeddings: Embeddings) -> Option<PlainDoc> {
+    let vector_canister: Principal = STATE.with(|state| (*state.borrow()).vector_canister.unwrap());
+    let query: VecQuery = VecQuery::Embeddings(embeddings);
+    let top_k: usize = 1;
+
+    let result: Result<(Option<Vec<PlainDoc>>,), _> =
+        ic_cdk::api::call::call(vector_canister, "search", (query, top_k)).await;
+    let (result,) = match result {
+        Ok(result) => result,
+        Err((code, message)) => {
+            ic_cdk::println!(
+                "call to vector_canister.search failed: {:?} {}",
+                code,
+                message
+            );
+            return None;
+        }
+    };
+
+    return result
+        .unwrap_or_default()
+        .into_iter()
+        .next()
+        .filter(|doc| doc.score.unwrap_or(0.0) >= DUPLICATE_SIMILARITY_THRESHOLD);
+}
+
+// Generates embeddings with the brain, reusing cached embeddings of identical content
+async fn generate_embeddings(goal_key: Option<u64>, content: String) -> Result<Embeddings, String> {
+    let content_hash: [u8; 32] = Sha256::digest(content.as_bytes()).into();
+    let cached_embeddings: Option<CachedEmbeddings> =
+        STATE.with(|s| s.borrow().stable_embeddings_cache_data.get(&content_hash));
+    if let Some(cached_embeddings) = cached_embeddings {
+        let embeddings: Embeddings = cached_embeddings.embeddings.clone();
+        cache_embeddings(content_hash, cached_embeddings);
+        return Ok(embeddings);
+    }
+
+    let brain_canister: Principal = STATE.with(|state| (*state.borrow()).brain_canister.unwrap());
+    let num_retries: i8 = 0;
+    let (result,): (Result<EmbeddingsResult, String>,) = ic_cdk::api::call::call(
+        brain_canister,
+        "generate_embeddings_with_usage",
+        (content, num_retries),
+    )
+    .await
+    .map_err(|(code, message)| {
+        format!(
+            "call to generate_embeddings_with_usage failed: {:?} {}",
+            code, message
+        )
+    })?;
+
+    let result: Result<Embeddings, String> = result.map(|result| {
+        record_usage(goal_key, CostSource::Embeddings, &result.usage);
+        result.embeddings
+    });
+
+    if let Ok(embeddings) = &result {
+        let new_cached_embeddings = CachedEmbeddings {
+            embeddings: embeddings.clone(),
+            created_at: time(),
+            last_used_at: None,
+        };
+        cache_embeddings(content_hash, new_cached_embeddings);
+    }
 
     return result;
 }
 
-async fn add_vecdoc(content: String, embeddings: Embeddings) -> String {
-    let vector_canister: Principal = STATE.with(|state| (*state.borrow()).vector_canister.unwrap());
-
-    let vec_doc = VecDoc {
-        content: content.clone(),
-        embeddings: embeddings.clone(),
-    };
+// Saves the embeddings as the most recently used, evicting the least recently used
+// embeddings once the cache is full
+fn cache_embeddings(content_hash: [u8; 32], cached_embeddings: CachedEmbeddings) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let now: Timestamp = time();
+
+        let prev_last_used_at: Option<Timestamp> = state
+            .stable_embeddings_cache_data
+            .get(&content_hash)
+            .map(|prev| prev.get_last_used_at());
+        match prev_last_used_at {
+            Some(prev_last_used_at) => {
+                state
+                    .stable_embeddings_cache_usage_data
+                    .remove(&(prev_last_used_at, content_hash));
+            }
+            None => {
+                while state.stable_embeddings_cache_data.len() >= MAX_EMBEDDINGS_CACHE_SIZE {
+                    let lru_key = match state.stable_embeddings_cache_usage_data.first_key_value() {
+                        Some((lru_key, _)) => lru_key,
+                        None => break,
+                    };
+                    state.stable_embeddings_cache_usage_data.remove(&lru_key);
+                    state.stable_embeddings_cache_data.remove(&lru_key.1);
+                }
+            }
+        }
+
+        let cached_embeddings = CachedEmbeddings {
+            last_used_at: Some(now),
+            ..cached_embeddings
+        };
+        state
+            .stable_embeddings_cache_data
+            .insert(content_hash, cached_embeddings);
+        state
+            .stable_embeddings_cache_usage_data
+            .insert((now, content_hash), ());
+    });
+}
+
+// Indexes embeddings cached before the cache was evicted by last use
+fn index_embeddings_cache_usage() {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if !state.stable_embeddings_cache_usage_data.is_empty()
+            || state.stable_embeddings_cache_data.is_empty()
+        {
+            return;
+        }
+
+        let usage_keys: Vec<(Timestamp, [u8; 32])> = state
+            .stable_embeddings_cache_data
+            .iter()
+            .map(|(content_hash, cached)| (cached.get_last_used_at(), content_hash))
+            .collect();
+        for usage_key in usage_keys {
+            state
+                .stable_embeddings_cache_usage_data
+                .insert(usage_key, ());
+        }
+    });
+}
+
+#[query]
+#[candid_method(query)]
+fn get_embeddings_cache_size() -> u64 {
+    STATE.with(|s| s.borrow().stable_embeddings_cache_data.len())
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn clear_embeddings_cache() {
+    STATE.with(|s| {
+        s.borrow_mut().stable_embeddings_cache_data =
+            StableBTreeMap::new(memory::get_stable_embeddings_cache_map_memory());
+        s.borrow_mut().stable_embeddings_cache_usage_data =
+            StableBTreeMap::new(memory::get_stable_embeddings_cache_usage_map_memory());
+    });
+}
+
+fn get_paymenttransction() -> Vec<PaymentTransaction> {
+    STATE.with(|s| s.borrow().stable_paymenttransaction_data.iter().collect())
+}
+
+// Retrieves goal from stable data
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_goal(key: u64) -> Option<Goal> {
+    STATE.with(|s| s.borrow().stable_goal_data.get(key))
+}
+
+// Retrieves chathistory from stable data
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_chathistory() -> Vec<ChatHistory> {
+    STATE.with(|s| s.borrow().stable_chathistory_data.iter().collect())
+}
+
+// Inserts a goal into the stable data Goal Vec and ChatHistory Vec
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn insert_goal(goal_string: String) {
+    let new_goal: Goal = create_goal(goal_string.clone());
+
+    let goal_key: u64 = push_goal(&new_goal);
+
+    insert_chat(goal_key, ChatRole::User, goal_string.clone());
+}
+
+// Inserts a goal into the stable data Goal Vec and ChatHistory Vec, and clear existing goals
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn start_new_goal(goal_string: String) {
+    let new_goal: Goal = create_goal(goal_string.clone());
+
+    clear_all_goals();
+
+    let goal_key: u64 = push_goal(&new_goal);
+
+    insert_chat(goal_key, ChatRole::User, goal_string.clone());
+
+    // run new goal in background
+    run_new_goal_async();
+}
+
+// Creates a Scheduled goal, called by controller itself
+fn create_goal(goal_string: String) -> Goal {
+    let now: Timestamp = time();
+    Goal {
+        goal: goal_string,
+        status: GoalStatus::Scheduled,
+        created_at: now,
+        updated_at: now,
+        result: None,
+        plan: None,
+        parent_goal_key: None,
+        agent_name: None,
+        agent_task: None,
+        max_num_thoughts: None,
+        num_thoughts: None,
+        waiting_on: None,
+        is_ask_user_enabled: None,
+        pending_question: None,
+        guidance: None,
+        is_debug_mode: None,
+        debug_step: None,
+        is_dry_run: None,
+        dry_run_actions: None,
+        cost: None,
+        budget: None,
+    }
+}
+
+fn push_goal(goal: &Goal) -> u64 {
+    STATE.with(|s| {
+        let state = s.borrow_mut();
+        state
+            .stable_goal_data
+            .push(goal)
+            .expect("call to insert_goal failed");
+        state.stable_goal_data.len() - 1
+    })
+}
+
+fn update_goal_status(index: u64, goal: Goal, status: GoalStatus) {
+    let updated_goal: Goal = Goal {
+        status: status,
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(index, &updated_goal));
+}
+
+// ---------------------- Child Agents ----------------------
+// Creates the goal of a child agent and runs its chain of thoughts in background
+fn start_child_agent(
+    parent_goal_key: u64,
+    name: String,
+    task: String,
+    prompt: String,
+) -> Result<u64, String> {
+    if get_child_goal_keys(parent_goal_key).len() >= MAX_NUM_CHILD_AGENTS_PER_GOAL {
+        return Err(format!(
+            "Agent {} was not started, no more than {} agents can be started per goal.",
+            name, MAX_NUM_CHILD_AGENTS_PER_GOAL
+        ));
+    }
+
+    let child_goal = Goal {
+        status: GoalStatus::Running,
+        parent_goal_key: Some(parent_goal_key),
+        agent_name: Some(name),
+        agent_task: Some(task),
+        max_num_thoughts: Some(MAX_NUM_COF_PER_CHILD_AGENT),
+        is_dry_run: is_goal_dry_run(parent_goal_key).then_some(true),
+        ..create_goal(prompt.clone())
+    };
+    let child_goal_key: u64 = push_goal(&child_goal);
+
+    insert_chat(child_goal_key, ChatRole::User, prompt.clone());
+
+    ic_cdk::spawn(async move {
+        let cof_input = create_cof_command(prompt.clone());
+        run_chain_of_thoughts(0, child_goal_key, cof_input, prompt).await;
+    });
+
+    Ok(child_goal_key)
+}
+
+fn get_child_goal_keys(goal_key: u64) -> Vec<u64> {
+    STATE.with(|s| {
+        s.borrow()
+            .stable_goal_data
+            .iter()
+            .enumerate()
+            .filter(|(_, goal)| goal.parent_goal_key == Some(goal_key))
+            .map(|(i, _)| i as u64)
+            .collect()
+    })
+}
+
+fn is_child_goal(goal_key: u64) -> bool {
+    STATE
+        .with(|s| s.borrow().stable_goal_data.get(goal_key))
+        .is_some_and(|goal| goal.parent_goal_key.is_some())
+}
+
+fn is_goal_complete(goal_key: u64) -> bool {
+    STATE
+        .with(|s| s.borrow().stable_goal_data.get(goal_key))
+        .is_some_and(|goal| goal.status == GoalStatus::Complete)
+}
+
+fn is_goal_running(goal_key: u64) -> bool {
+    STATE
+        .with(|s| s.borrow().stable_goal_data.get(goal_key))
+        .is_some_and(|goal| goal.status == GoalStatus::Running)
+}
+
+// Completes a child agent with the error it stopped on and reports it to its parent
+fn fail_child_goal(goal_key: u64, error: &str) {
+    let result = format!("Agent failed: {}", error);
+    insert_chat(goal_key, ChatRole::System, result.clone());
+    save_result(goal_key, result);
+    report_to_parent_goal(goal_key);
+}
+
+// Fails the agents a goal has waited on for longer than WAIT_FOR_AGENTS_TIMEOUT_SECS,
+// which resumes the goal with their results
+fn fail_timed_out_child_goals() {
+    let now: Timestamp = time();
+    let timeout: Timestamp = WAIT_FOR_AGENTS_TIMEOUT_SECS * 1_000_000_000;
+    let timed_out_keys: Vec<u64> = STATE.with(|s| {
+        s.borrow()
+            .stable_goal_data
+            .iter()
+            .filter(|goal| {
+                goal.status == GoalStatus::Waiting && now.saturating_sub(goal.updated_at) >= timeout
+            })
+            .filter_map(|goal| goal.waiting_on)
+            .flatten()
+            .collect()
+    });
+
+    for goal_key in timed_out_keys {
+        if !is_goal_complete(goal_key) {
+            fail_child_goal(
+                goal_key,
+                &format!(
+                    "Agent has not finished within {} minutes.",
+                    WAIT_FOR_AGENTS_TIMEOUT_SECS / 60
+                ),
+            );
+        }
+    }
+}
+
+// Accepts a goal key either as a number or a string
+fn parse_goal_key(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+// Chats of a child agent are kept apart from the chats of its parent,
+// which only sees the result reported back by the child agent
+fn get_goal_chathistory(goal_key: u64) -> Vec<ChatHistory> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let child_goal_keys: Vec<u64> = state
+            .stable_goal_data
+            .iter()
+            .enumerate()
+            .filter(|(_, goal)| goal.parent_goal_key.is_some())
+            .map(|(i, _)| i as u64)
+            .collect();
+        let is_child = child_goal_keys.contains(&goal_key);
+
+        state
+            .stable_chathistory_data
+            .iter()
+            .filter(|chat| match chat.goal_key {
+                Some(key) if is_child => key == goal_key,
+                Some(key) => !child_goal_keys.contains(&key),
+                None => !is_child,
+            })
+            .collect()
+    })
+}
+
+// Parks a goal until its child agents have finished
+fn wait_for_child_goals(goal_key: u64, num_thoughts: u16, child_goal_keys: Vec<u64>) {
+    let opt_goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(goal_key));
+
+    if let Some(my_goal) = opt_goal {
+        let updated_goal: Goal = Goal {
+            status: GoalStatus::Waiting,
+            num_thoughts: Some(num_thoughts),
+            waiting_on: Some(child_goal_keys),
+            updated_at: time(),
+            ..my_goal
+        };
+        STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+    }
+}
+
+// Reports the result of a finished child agent to its parent, and resumes the parent
+// once all the child agents it waits on have finished
+fn report_to_parent_goal(goal_key: u64) {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return,
+    };
+    let parent_goal_key: u64 = match goal.parent_goal_key {
+        Some(parent_goal_key) => parent_goal_key,
+        None => return,
+    };
+
+    insert_chat(
+        parent_goal_key,
+        ChatRole::System,
+        format!(
+            "Agent {} with agent id {} has finished with result: {}",
+            goal.agent_name.unwrap_or_default(),
+            goal_key,
+            goal.result.unwrap_or_default()
+        ),
+    );
+
+    let parent_goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(parent_goal_key)) {
+        Some(parent_goal) => parent_goal,
+        None => return,
+    };
+    let is_ready = parent_goal.status == GoalStatus::Waiting
+        && parent_goal
+            .waiting_on
+            .as_ref()
+            .is_none_or(|keys| keys.iter().all(|key| is_goal_complete(*key)));
+    if !is_ready {
+        return;
+    }
+
+    resume_goal(parent_goal_key, parent_goal);
+}
+
+// Runs the chain of thoughts of a Waiting goal again from where it stopped
+fn resume_goal(goal_key: u64, goal: Goal) {
+    let num_thoughts: u16 = goal.num_thoughts.unwrap_or_default();
+    let cof_input = create_cof_command(goal.goal.clone());
+    continue_goal(goal_key, goal, num_thoughts + 1, cof_input);
+}
+
+// Runs the given command of a Waiting goal in background
+fn continue_goal(goal_key: u64, goal: Goal, num_thoughts: u16, cof_input: String) {
+    let main_goal: String = goal.goal.clone();
+    let updated_goal: Goal = Goal {
+        status: GoalStatus::Running,
+        waiting_on: None,
+        pending_question: None,
+        debug_step: None,
+        updated_at: time(),
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    ic_cdk::spawn(async move {
+        run_chain_of_thoughts(num_thoughts, goal_key, cof_input, main_goal).await;
+    });
+}
+
+// ---------------------- Debug Mode ----------------------
+// Parks a goal in debug mode with the prompt and decision of its last step
+fn wait_for_debug_step(goal_key: u64, num_thoughts: u16, prompt: String, raw_output: String) {
+    let opt_goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(goal_key));
+
+    if let Some(my_goal) = opt_goal {
+        let command: Option<String> = serde_json::from_str::<serde_json::Value>(&raw_output)
+            .ok()
+            .map(|cof_json| cof_json["command"].to_string());
+        let now: Timestamp = time();
+        let updated_goal: Goal = Goal {
+            status: GoalStatus::Waiting,
+            num_thoughts: Some(num_thoughts),
+            debug_step: Some(DebugStep {
+                prompt,
+                raw_output,
+                command,
+                num_thoughts,
+                created_at: now,
+            }),
+            updated_at: now,
+            ..my_goal
+        };
+        STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+    }
+}
+
+// Makes the goal stop after each decision of the LLM until the owner steps through it
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn set_goal_debug_mode(goal_key: u64, is_enabled: bool) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+
+    let updated_goal: Goal = Goal {
+        is_debug_mode: Some(is_enabled),
+        updated_at: time(),
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    return Ok(());
+}
+
+// Retrieves the rendered prompt, raw output and parsed command of the step waiting to run
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_goal_debug_step(goal_key: u64) -> Option<DebugStep> {
+    STATE
+        .with(|s| s.borrow().stable_goal_data.get(goal_key))
+        .and_then(|goal| goal.debug_step)
+}
+
+// Runs the command of the waiting step. An edited command replaces the one decided by the LLM,
+// given either as a whole response or only its {"name": .., "args": ..} command.
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn step_goal(goal_key: u64, edited_command: Option<String>) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+    let debug_step: DebugStep = match goal.debug_step.clone() {
+        Some(debug_step) => debug_step,
+        None => return Err("Goal has no step waiting to run.".to_string()),
+    };
+
+    let cof_input: String = match edited_command {
+        Some(edited_command) => {
+            let cof_json = serde_json::from_str::<serde_json::Value>(&edited_command)
+                .map_err(|e| format!("Invalid command JSON: {}", e))?;
+            let cof_input = if cof_json.get("command").is_some() {
+                edited_command
+            } else {
+                json!({ "command": cof_json }).to_string()
+            };
+            insert_chat(
+                goal_key,
+                ChatRole::System,
+                format!("The owner replaced the command with: {}", cof_input),
+            );
+            cof_input
+        }
+        None => debug_step.raw_output,
+    };
+
+    continue_goal(goal_key, goal, debug_step.num_thoughts, cof_input);
+
+    return Ok(());
+}
+
+// Completes the goal waiting in debug mode without running its command
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn abort_goal(goal_key: u64) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+    if goal.debug_step.is_none() {
+        return Err("Goal has no step waiting to run.".to_string());
+    }
+
+    let updated_goal: Goal = Goal {
+        debug_step: None,
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    let result = "ArcMind AI was aborted by the owner. End of processing.".to_string();
+    insert_chat(goal_key, ChatRole::System, result.clone());
+    save_result(goal_key, result);
+    report_to_parent_goal(goal_key);
+
+    return Ok(());
+}
+
+// ---------------------- Step Trace ----------------------
+// Starts tracing a step of the goal, ending the trace of its previous step
+fn begin_step_trace(goal_key: u64, step: u16, command: Option<&str>, args: String) {
+    finish_step_trace(goal_key);
+
+    let now: Timestamp = time();
+    let pending = PendingStepTrace {
+        trace: StepTrace {
+            goal_key,
+            step,
+            command: command.map(|command| command.to_string()),
+            args: truncate_trace_args(&args),
+            result_size: 0,
+            model: None,
+            latency_ns: 0,
+            cycles_used: 0,
+            outcome: StepOutcome::Ok,
+            created_at: now,
+        },
+        started_at: now,
+        start_cycles_balance: ic_cdk::api::canister_balance(),
+    };
+    PENDING_STEP_TRACES.with(|p| p.borrow_mut().insert(goal_key, pending));
+}
+
+fn trace_step_result(goal_key: u64, result_size: usize, model: Option<String>) {
+    PENDING_STEP_TRACES.with(|p| {
+        if let Some(pending) = p.borrow_mut().get_mut(&goal_key) {
+            pending.trace.result_size = result_size as u64;
+            pending.trace.model = model;
+        }
+    });
+}
+
+fn set_step_outcome(goal_key: u64, outcome: StepOutcome) {
+    PENDING_STEP_TRACES.with(|p| {
+        if let Some(pending) = p.borrow_mut().get_mut(&goal_key) {
+            pending.trace.outcome = outcome;
+        }
+    });
+}
+
+// Saves the trace of the current step of the goal with its latency and approximate cycles.
+// Cycles are measured from the controller balance, so steps of goals running at the same time
+// may count part of each other's cycles.
+fn finish_step_trace(goal_key: u64) {
+    let pending: Option<PendingStepTrace> =
+        PENDING_STEP_TRACES.with(|p| p.borrow_mut().remove(&goal_key));
+
+    if let Some(pending) = pending {
+        let trace = StepTrace {
+            latency_ns: time().saturating_sub(pending.started_at),
+            cycles_used: pending
+                .start_cycles_balance
+                .saturating_sub(ic_cdk::api::canister_balance()),
+            ..pending.trace
+        };
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            let index: u64 = state.stable_step_trace_data.len();
+            state
+                .stable_step_trace_data
+                .push(&trace)
+                .expect("call to push step trace fails");
+            state
+                .stable_step_trace_index_data
+                .insert((goal_key, index), ());
+        });
+
+        let controller_cost = Cost {
+            controller_cycles: trace.cycles_used,
+            ..Default::default()
+        };
+        record_cost(Some(goal_key), &controller_cost);
+    }
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_goal_step_traces(goal_key: u64) -> Vec<StepTrace> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        state
+            .stable_step_trace_index_data
+            .range((goal_key, 0)..=(goal_key, u64::MAX))
+            .filter_map(|((_, index), _)| state.stable_step_trace_data.get(index))
+            .collect()
+    })
+}
+
+fn count_goal_step_traces(goal_key: u64) -> u64 {
+    STATE.with(|s| {
+        s.borrow()
+            .stable_step_trace_index_data
+            .range((goal_key, 0)..=(goal_key, u64::MAX))
+            .count() as u64
+    })
+}
+
+// Indexes step traces saved before they were indexed by goal
+fn index_step_traces() {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if !state.stable_step_trace_index_data.is_empty() {
+            return;
+        }
+
+        let keys: Vec<(u64, u64)> = state
+            .stable_step_trace_data
+            .iter()
+            .enumerate()
+            .map(|(index, trace)| (trace.goal_key, index as u64))
+            .collect();
+        for key in keys {
+            state.stable_step_trace_index_data.insert(key, ());
+        }
+    });
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_step_traces(offset: u64, limit: u64) -> Vec<StepTrace> {
+    STATE.with(|s| {
+        s.borrow()
+            .stable_step_trace_data
+            .iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_MEMORY_PAGE_SIZE) as usize)
+            .collect()
+    })
+}
+
+// Number of steps, errors, recoveries, latency and cycles per command, of one goal or all goals
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_step_trace_stats(goal_key: Option<u64>) -> Vec<CommandTraceStats> {
+    let traces: Vec<StepTrace> = match goal_key {
+        Some(goal_key) => get_goal_step_traces(goal_key),
+        None => STATE.with(|s| s.borrow().stable_step_trace_data.iter().collect()),
+    };
+
+    summarize_step_traces(&traces)
+}
+
+// ---------------------- Cost Accounting ----------------------
+fn record_usage(goal_key: Option<u64>, source: CostSource, usage: &Usage) {
+    record_cost(goal_key, &create_usage_cost(source, usage));
+}
+
+// Adds the cost to the goal, if any, and to the cost of the current day
+fn record_cost(goal_key: Option<u64>, cost: &Cost) {
+    let now: Timestamp = time();
+
+    if let Some(goal_key) = goal_key {
+        let opt_goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(goal_key));
+        if let Some(my_goal) = opt_goal {
+            let mut goal_cost: Cost = my_goal.cost.clone().unwrap_or_default();
+            add_cost(&mut goal_cost, cost);
+            let updated_goal: Goal = Goal {
+                cost: Some(goal_cost),
+                ..my_goal
+            };
+            STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+        }
+    }
+
+    STATE.with(|s| add_period_cost(&mut s.borrow_mut().period_costs, now, cost));
+}
+
+// Cost of the goal, including the child agents it started
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_goal_cost(goal_key: u64) -> Option<Cost> {
+    let goal: Goal = STATE.with(|s| s.borrow().stable_goal_data.get(goal_key))?;
+
+    let mut total: Cost = goal.cost.unwrap_or_default();
+    for child_goal_key in get_child_goal_keys(goal_key) {
+        if let Some(child_cost) = get_goal_cost(child_goal_key) {
+            add_cost(&mut total, &child_cost);
+        }
+    }
+
+    Some(total)
+}
+
+// Cost of all goals and owner requests per day, for the days between from and to
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_cost_report(from: Timestamp, to: Timestamp) -> CostReport {
+    let from_period_start: Timestamp = get_period_start(from);
+    let periods: Vec<PeriodCost> = STATE.with(|s| {
+        s.borrow()
+            .period_costs
+            .iter()
+            .filter(|period| period.period_start >= from_period_start && period.period_start <= to)
+            .cloned()
+            .collect()
+    });
+
+    let mut total = Cost::default();
+    for period in periods.iter() {
+        add_cost(&mut total, &period.cost);
+    }
+
+    CostReport {
+        from,
+        to,
+        total,
+        periods,
+    }
+}
+
+// ---------------------- Model Routing ----------------------
+// Model and temperature of a step, escalated for main reasoning steps of the goal after
+// repeated recoveries. Web page extraction falls back to browse_website_gpt_model.
+fn get_model_route(goal_key: Option<u64>, step_kind: StepKind) -> ModelRoute {
+    let (route, escalation, browse_website_gpt_model) = STATE.with(|s| {
+        let state = s.borrow();
+        (
+            find_model_route(&state.model_routes, step_kind),
+            state.model_escalation.clone(),
+            state.browse_website_gpt_model.clone(),
+        )
+    });
+
+    let route: ModelRoute = match route.model {
+        None if step_kind == StepKind::WebExtraction => ModelRoute {
+            model: browse_website_gpt_model,
+            ..route
+        },
+        _ => route,
+    };
+
+    let num_trailing_recoveries: u16 = match (goal_key, escalation.as_ref()) {
+        (Some(goal_key), Some(_)) => count_trailing_recoveries(&get_goal_step_traces(goal_key)),
+        _ => 0,
+    };
+
+    escalate_model_route(route, escalation.as_ref(), num_trailing_recoveries)
+}
+
+// Routes a step kind to a model and temperature, replacing its previous route
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn set_model_route(route: ModelRoute) -> Result<(), String> {
+    validate_model_route(&route)?;
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state
+            .model_routes
+            .retain(|existing| existing.step_kind != route.step_kind);
+        state.model_routes.push(route);
+    });
+
+    return Ok(());
+}
+
+// Routes the step kind back to the default model of the brain
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn remove_model_route(step_kind: StepKind) {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .model_routes
+            .retain(|route| route.step_kind != step_kind)
+    });
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_model_routes() -> Vec<ModelRoute> {
+    STATE.with(|s| s.borrow().model_routes.clone())
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn set_model_escalation(escalation: Option<ModelEscalation>) -> Result<(), String> {
+    if let Some(escalation) = escalation.as_ref() {
+        if escalation.after_num_recoveries == 0 {
+            return Err("Escalation must happen after at least 1 recovery.".to_string());
+        }
+        validate_model_route(&ModelRoute {
+            step_kind: StepKind::Main,
+            model: Some(escalation.model.clone()),
+            temperature: escalation.temperature,
+        })?;
+    }
+
+    STATE.with(|s| s.borrow_mut().model_escalation = escalation);
+
+    return Ok(());
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_model_escalation() -> Option<ModelEscalation> {
+    STATE.with(|s| s.borrow().model_escalation.clone())
+}
+
+// ---------------------- Budgets ----------------------
+// Checks the budget of the goal, and of its parent goals which include its cost
+fn find_goal_exceeded_budget(goal_key: u64) -> Option<String> {
+    let goal: Goal = STATE.with(|s| s.borrow().stable_goal_data.get(goal_key))?;
+    let prices: Option<CostPrices> = STATE.with(|s| s.borrow().cost_prices.clone());
+
+    if let Some(budget) = goal.budget.as_ref() {
+        let cost: Cost = get_goal_cost(goal_key).unwrap_or_default();
+        if let Some(exceeded_budget) = find_exceeded_budget(&cost, budget, prices.as_ref()) {
+            return Some(exceeded_budget);
+        }
+    }
+
+    goal.parent_goal_key.and_then(find_goal_exceeded_budget)
+}
+
+// Sets the budget of a goal, checked before each of its steps
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn set_goal_budget(goal_key: u64, budget: Option<GoalBudget>) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+    if goal.status == GoalStatus::Complete {
+        return Err("Goal is already complete.".to_string());
+    }
+    let is_usd_budget: bool = budget
+        .as_ref()
+        .is_some_and(|budget| budget.max_usd.is_some());
+    if is_usd_budget && STATE.with(|s| s.borrow().cost_prices.is_none()) {
+        return Err("Set the cost prices before a budget in USD.".to_string());
+    }
+
+    let updated_goal: Goal = Goal {
+        budget,
+        updated_at: time(),
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    return Ok(());
+}
+
+// Estimates the cost of a new goal from the cost and number of steps of completed goals
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn estimate_new_goal_cost() -> Option<CostEstimate> {
+    let costs: Vec<(u64, Cost)> = STATE.with(|s| {
+        s.borrow()
+            .stable_goal_data
+            .iter()
+            .enumerate()
+            .filter(|(_, goal)| {
+                goal.status == GoalStatus::Complete && goal.parent_goal_key.is_none()
+            })
+            .filter_map(|(i, goal)| goal.cost.map(|cost| (i as u64, cost)))
+            .collect()
+    });
+    let samples: Vec<(Cost, u64)> = costs
+        .into_iter()
+        .map(|(goal_key, cost)| (cost, count_goal_step_traces(goal_key)))
+        .collect();
+    let prices: Option<CostPrices> = STATE.with(|s| s.borrow().cost_prices.clone());
+
+    estimate_goal_cost(&samples, MAX_NUM_COF_PER_GOAL as u64, prices.as_ref())
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn set_cost_prices(prices: Option<CostPrices>) {
+    STATE.with(|s| s.borrow_mut().cost_prices = prices);
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_cost_prices() -> Option<CostPrices> {
+    STATE.with(|s| s.borrow().cost_prices.clone())
+}
+
+// ---------------------- Dry Run ----------------------
+fn is_goal_dry_run(goal_key: u64) -> bool {
+    STATE
+        .with(|s| s.borrow().stable_goal_data.get(goal_key))
+        .and_then(|goal| goal.is_dry_run)
+        .unwrap_or(false)
+}
+
+fn record_dry_run_action(goal_key: u64, command: &str, args: String, result: String) {
+    let opt_goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(goal_key));
+
+    if let Some(my_goal) = opt_goal {
+        let now: Timestamp = time();
+        let mut dry_run_actions: Vec<DryRunAction> =
+            my_goal.dry_run_actions.clone().unwrap_or_default();
+        dry_run_actions.push(DryRunAction {
+            command: command.to_string(),
+            args,
+            result,
+            created_at: now,
+        });
+        let updated_goal: Goal = Goal {
+            dry_run_actions: Some(dry_run_actions),
+            updated_at: now,
+            ..my_goal
+        };
+        STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+    }
+}
+
+// Answers a google query or browsed url from the recorded fixtures, and records the action
+fn get_dry_run_output(goal_key: u64, command: &str, input: &str) -> String {
+    let output: String = STATE
+        .with(|s| find_fixture_output(&s.borrow().dry_run_fixtures, command, input))
+        .unwrap_or(format!("No recorded result of {} for: {}", command, input));
+
+    let args = json!({ "input": input }).to_string();
+    record_dry_run_action(goal_key, command, args, output.clone());
+
+    return output;
+}
+
+// Runs the goal without side effects: payments and other plugin commands are only recorded,
+// google and browse_website are answered from recorded fixtures and memory is not written.
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn set_goal_dry_run(goal_key: u64, is_enabled: bool) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+    if goal.status == GoalStatus::Complete {
+        return Err("Goal is already complete.".to_string());
+    }
+
+    let updated_goal: Goal = Goal {
+        is_dry_run: Some(is_enabled),
+        updated_at: time(),
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    return Ok(());
+}
+
+// Same as start_new_goal, with the new goal running in dry run
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn start_new_dry_run_goal(goal_string: String) {
+    let new_goal: Goal = Goal {
+        is_dry_run: Some(true),
+        ..create_goal(goal_string.clone())
+    };
+
+    clear_all_goals();
+
+    let goal_key: u64 = push_goal(&new_goal);
+
+    insert_chat(goal_key, ChatRole::User, goal_string.clone());
+
+    // run new goal in background
+    run_new_goal_async();
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_goal_dry_run_actions(goal_key: u64) -> Vec<DryRunAction> {
+    STATE
+        .with(|s| s.borrow().stable_goal_data.get(goal_key))
+        .and_then(|goal| goal.dry_run_actions)
+        .unwrap_or_default()
+}
+
+// Records the output returned for a google query or browse_website url in dry run,
+// replacing the output recorded before for the same input
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn add_dry_run_fixture(command: String, input: String, output: String) -> Result<(), String> {
+    if command != PROMPT_CMD_GOOGLE && command != PROMPT_CMD_BROWSE_WEBSITE {
+        return Err(format!(
+            "Fixtures are only recorded for {} and {}.",
+            PROMPT_CMD_GOOGLE, PROMPT_CMD_BROWSE_WEBSITE
+        ));
+    }
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state
+            .dry_run_fixtures
+            .retain(|fixture| !is_fixture_match(fixture, &command, &input));
+        state.dry_run_fixtures.push(DryRunFixture {
+            command,
+            input,
+            output,
+        });
+    });
+
+    return Ok(());
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn list_dry_run_fixtures() -> Vec<DryRunFixture> {
+    STATE.with(|s| s.borrow().dry_run_fixtures.clone())
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn clear_dry_run_fixtures() {
+    STATE.with(|s| s.borrow_mut().dry_run_fixtures.clear());
+}
+
+// ---------------------- Guidance from the Owner ----------------------
+// Adds guidance to a goal, placed prominently in its next prompt.
+// Sticky guidance is repeated in every later prompt until it is cleared.
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn add_goal_guidance(goal_key: u64, text: String, is_sticky: bool) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+    if goal.status == GoalStatus::Complete {
+        return Err("Goal is already complete.".to_string());
+    }
+
+    let now: Timestamp = time();
+    let mut guidance: Vec<GoalGuidance> = goal.guidance.clone().unwrap_or_default();
+    guidance.push(GoalGuidance {
+        text: text.clone(),
+        is_sticky,
+        is_delivered: false,
+        created_at: now,
+    });
+    let updated_goal: Goal = Goal {
+        guidance: Some(guidance),
+        updated_at: now,
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    insert_chat(goal_key, ChatRole::User, format!("Guidance: {}", text));
+
+    return Ok(());
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_goal_guidance(goal_key: u64) -> Vec<GoalGuidance> {
+    STATE
+        .with(|s| s.borrow().stable_goal_data.get(goal_key))
+        .and_then(|goal| goal.guidance)
+        .unwrap_or_default()
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn clear_goal_guidance(goal_key: u64) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+
+    let updated_goal: Goal = Goal {
+        guidance: None,
+        updated_at: time(),
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    return Ok(());
+}
+
+// Returns the guidance for the next prompt of a goal, marking it as delivered
+fn take_goal_guidance(goal_key: u64) -> Vec<GoalGuidance> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Vec::new(),
+    };
+    let mut guidance: Vec<GoalGuidance> = goal.guidance.clone().unwrap_or_default();
+
+    let next_guidance: Vec<GoalGuidance> = guidance
+        .iter()
+        .filter(|guidance| guidance.is_sticky || !guidance.is_delivered)
+        .cloned()
+        .collect();
+    if next_guidance.is_empty() {
+        return next_guidance;
+    }
+
+    for guidance in guidance.iter_mut() {
+        guidance.is_delivered = true;
+    }
+    let updated_goal: Goal = Goal {
+        guidance: Some(guidance),
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    next_guidance
+}
+
+// ---------------------- Questions to the Owner ----------------------
+// Parks a goal until the owner replies to its question
+fn wait_for_user_reply(goal_key: u64, num_thoughts: u16, question: String) {
+    let opt_goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(goal_key));
+
+    if let Some(my_goal) = opt_goal {
+        let updated_goal: Goal = Goal {
+            status: GoalStatus::Waiting,
+            num_thoughts: Some(num_thoughts),
+            pending_question: Some(question),
+            updated_at: time(),
+            ..my_goal
+        };
+        STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+    }
+}
+
+// Allows or disallows the agent to ask the owner questions while working on a goal
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn set_goal_ask_user(goal_key: u64, is_enabled: bool) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+
+    let updated_goal: Goal = Goal {
+        is_ask_user_enabled: Some(is_enabled),
+        updated_at: time(),
+        ..goal
+    };
+    STATE.with(|s| s.borrow_mut().stable_goal_data.set(goal_key, &updated_goal));
+
+    return Ok(());
+}
+
+// Lists the goals waiting for the owner to answer their question
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_pending_questions() -> Vec<(u64, String)> {
+    STATE.with(|s| {
+        s.borrow()
+            .stable_goal_data
+            .iter()
+            .enumerate()
+            .filter_map(|(i, goal)| goal.pending_question.map(|question| (i as u64, question)))
+            .collect()
+    })
+}
+
+// Answers the question of a waiting goal and resumes it
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn reply_to_goal(goal_key: u64, answer: String) -> Result<(), String> {
+    let goal: Goal = match STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)) {
+        Some(goal) => goal,
+        None => return Err("Goal not found.".to_string()),
+    };
+    if goal.status != GoalStatus::Waiting || goal.pending_question.is_none() {
+        return Err("Goal is not waiting for a reply.".to_string());
+    }
+
+    insert_chat(goal_key, ChatRole::User, answer);
+    resume_goal(goal_key, goal);
+
+    return Ok(());
+}
+
+// Retrieves the plan of subtasks of a goal
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_goal_plan(key: u64) -> Option<Vec<Subtask>> {
+    STATE
+        .with(|s| s.borrow().stable_goal_data.get(key))
+        .and_then(|goal| goal.plan)
+}
+
+// Replaces the plan of subtasks of a goal, called by controller itself
+fn update_goal_plan(key: u64, plan: Option<Vec<Subtask>>) {
+    let opt_goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(key));
+
+    if let Some(my_goal) = opt_goal {
+        let updated_goal: Goal = Goal {
+            plan,
+            updated_at: time(),
+            ..my_goal
+        };
+        STATE.with(|s| s.borrow_mut().stable_goal_data.set(key, &updated_goal));
+    }
+}
+
+// Complete a goal with result, called by controller itself
+fn save_result(key: u64, result: String) {
+    let opt_goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(key));
+
+    match opt_goal {
+        Some(my_goal) => {
+            // a dry run ends with the report of the commands it simulated
+            let result: String = if my_goal.is_dry_run.unwrap_or(false) {
+                let report =
+                    format_dry_run_report(&my_goal.dry_run_actions.clone().unwrap_or_default());
+                insert_chat(key, ChatRole::System, report.clone());
+                format!("{}\n\n{}", result, report)
+            } else {
+                result
+            };
+
+            let now: Timestamp = time();
+            let updated_goal: Goal = Goal {
+                result: Some(result),
+                status: GoalStatus::Complete,
+                updated_at: now,
+                ..my_goal
+            };
+
+            STATE.with(|s| s.borrow_mut().stable_goal_data.set(key, &updated_goal));
+
+            // schedule the workflow goals that depend on this goal
+            advance_workflow_runs();
+        }
+        None => {
+            ic_cdk::trap("Goal not found.");
+        }
+    }
+}
+
+// Insert chat of a goal, called by controller itself
+fn insert_chat(goal_key: u64, role: ChatRole, content: String) {
+    let now: Timestamp = time();
+    let new_chat = ChatHistory {
+        content: content,
+        role: role,
+        created_at: now,
+        goal_key: Some(goal_key),
+    };
+
+    STATE.with(|s| {
+        s.borrow_mut()
+            .stable_chathistory_data
+            .push(&new_chat)
+            .expect("call to insert_chat failed")
+    });
+}
+
+fn write_file_and_shutdown(goal_key: u64, _key: String, text: String) {
+    insert_chat(goal_key, ChatRole::ArcMind, text);
+}
+
+async fn google(goal_key: Option<u64>, query: String) -> String {
+    let tools_canister: Principal = STATE.with(|state| (*state.borrow()).tools_canister.unwrap());
+    let (result,): (ToolResult,) =
+        ic_cdk::api::call::call(tools_canister, "google_with_usage", (query,))
+            .await
+            .expect("call to google_with_usage failed");
+
+    record_usage(goal_key, CostSource::Tools, &result.usage);
+
+    return result.content;
+}
+
+async fn browse_website(goal_key: Option<u64>, url: String, _question: String) -> String {
+    let tools_canister: Principal = STATE.with(|state| (*state.borrow()).tools_canister.unwrap());
+    let (result,): (ToolResult,) =
+        ic_cdk::api::call::call(tools_canister, "browse_website_with_usage", (url,))
+            .await
+            .expect("call to browse_website_with_usage failed");
+
+    record_usage(goal_key, CostSource::Tools, &result.usage);
+
+    return result.content;
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn clear_all_goals() {
+    // clear and reinit stable_chathistory_data and stable_goal_data
+    STATE.with(|s| {
+        s.borrow_mut().stable_chathistory_data =
+            StableVec::new(memory::get_stable_chathistory_vec_memory())
+                .expect("call to get_stable_goal_vec_memory fails");
+        s.borrow_mut().stable_goal_data = StableVec::new(memory::get_stable_goal_vec_memory())
+            .expect("call to get_stable_goal_vec_memory fails");
+        // workflow and schedule runs refer to the cleared goals
+        s.borrow_mut().workflow_runs.clear();
+        for schedule in s.borrow_mut().goal_schedules.iter_mut() {
+            schedule.runs.clear();
+        }
+        for watcher in s.borrow_mut().watchers.iter_mut() {
+            watcher.runs.clear();
+        }
+        s.borrow_mut().stable_step_trace_data =
+            StableVec::new(memory::get_stable_step_trace_vec_memory())
+                .expect("call to get_stable_step_trace_vec_memory fails");
+        s.borrow_mut().stable_step_trace_index_data =
+            StableBTreeMap::new(memory::get_stable_step_trace_index_map_memory());
+    });
+    PENDING_STEP_TRACES.with(|p| p.borrow_mut().clear());
+}
+
+// ---------------------- Long Term Memory Management ----------------------
+/*
+ * Calls a method of the vector canister memory API beyond add and search.
+ * Fails without calling when the vector canister is not known to implement it,
+ * and returns rejected calls as errors instead of trapping.
+ */
+async fn call_vector_memory_api<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    method: &str,
+    args: T,
+) -> Result<R, String> {
+    let (vector_canister, is_enabled) = STATE.with(|state| {
+        let state = state.borrow();
+        (
+            state.vector_canister.unwrap(),
+            state.is_vector_memory_api_enabled,
+        )
+    });
+    if !is_enabled {
+        return Err(format!(
+            "The vector canister memory API is not enabled, {} is unavailable.",
+            method
+        ));
+    }
+
+    return ic_cdk::api::call::call(vector_canister, method, args)
+        .await
+        .map_err(|(code, message)| {
+            format!(
+                "call to vector_canister.{} failed: {:?} {}",
+                method, code, message
+            )
+        });
+}
+
+// Lists long term memories in the vector canister, paginated
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn list_memories(offset: u64, limit: u64) -> Result<MemoryPage, String> {
+    let safe_limit = limit.min(MAX_MEMORY_PAGE_SIZE);
+
+    let (result,): (MemoryPage,) = call_vector_memory_api("list", (offset, safe_limit)).await?;
+
+    return Ok(result);
+}
+
+// Searches long term memories by text, using the same embeddings as the chain of thoughts
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn search_memories(text: String, top_k: u64) -> Result<Vec<MemoryDoc>, String> {
+    let embeddings: Embeddings = generate_embeddings(None, text.clone()).await?;
+
+    let query: VecQuery = VecQuery::Embeddings(embeddings);
+    let safe_top_k = top_k.min(MAX_MEMORY_PAGE_SIZE) as usize;
+
+    let (result,): (Vec<MemoryDoc>,) =
+        call_vector_memory_api("search_docs", (query, safe_top_k)).await?;
+
+    return Ok(result);
+}
+
+// Replaces the content of a long term memory and re-generates its embeddings
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn update_memory(id: u64, content: String) -> Result<(), String> {
+    let embeddings: Embeddings = generate_embeddings(None, content.clone()).await?;
+
+    let (result,): (Result<(), String>,) =
+        call_vector_memory_api("update", (id, content, embeddings)).await?;
+
+    return result;
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn delete_memory(id: u64) -> Result<(), String> {
+    let (result,): (Result<(), String>,) = call_vector_memory_api("delete", (id,)).await?;
+
+    return result;
+}
+
+// Deletes all long term memories of a namespace e.g google, browse_website
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn delete_memory_namespace(namespace: String) -> Result<u64, String> {
+    let (result,): (Result<u64, String>,) =
+        call_vector_memory_api("delete_namespace", (namespace,)).await?;
+
+    return result;
+}
+
+// Pinned memories are always included in the chain of thoughts prompt
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn pin_memory(id: u64, is_pinned: bool) -> Result<(), String> {
+    let (result,): (Result<(), String>,) = call_vector_memory_api("pin", (id, is_pinned)).await?;
+
+    return result;
+}
+
+// Archives or deletes memories whose retention score fell below the fade policy threshold.
+// Pinned memories and owner seeded documents never fade.
+async fn fade_memories() {
+    let fade_policy: Option<MemoryFadePolicy> =
+        STATE.with(|state| (*state.borrow()).memory_fade_policy.clone());
+    let fade_policy = match fade_policy {
+        Some(fade_policy) => fade_policy,
+        None => return,
+    };
+
+    let now: Timestamp = time();
+    let mut faded_ids: Vec<u64> = Vec::new();
+    let mut offset: u64 = 0;
+
+    loop {
+        let page: MemoryPage = match list_memories(offset, MAX_MEMORY_PAGE_SIZE).await {
+            Ok(page) => page,
+            Err(e) => {
+                ic_cdk::println!("Failed to list memories to fade: {}", e);
+                return;
+            }
+        };
+        let num_docs = page.docs.len() as u64;
+
+        for doc in page.docs {
+            if doc.is_pinned || doc.namespace.as_deref() == Some(VEC_NAMESPACE_DOCUMENT) {
+                continue;
+            }
+
+            let retention =
+                retention_score(doc.importance, doc.last_accessed_at, doc.created_at, now);
+            if retention < fade_policy.threshold {
+                faded_ids.push(doc.id);
+            }
+        }
+
+        offset += num_docs;
+        if num_docs == 0 || offset >= page.total {
+            break;
+        }
+    }
+
+    ic_cdk::println!("Fading {} memories", faded_ids.len());
+
+    let method = match fade_policy.action {
+        FadeAction::Archive => "archive",
+        FadeAction::Delete => "delete",
+    };
+
+    for id in faded_ids {
+        let result: Result<(Result<(), String>,), String> =
+            call_vector_memory_api(method, (id,)).await;
+
+        if let Err(e) = result.and_then(|(result,)| result) {
+            ic_cdk::println!("Failed to fade memory {}: {}", id, e);
+        }
+    }
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+pub fn update_memory_fade_policy(new_policy: Option<MemoryFadePolicy>) {
+    STATE.with(|state| {
+        state.borrow_mut().memory_fade_policy = new_policy;
+    });
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_memory_fade_policy() -> Option<MemoryFadePolicy> {
+    STATE.with(|state| (*state.borrow()).memory_fade_policy.clone())
+}
+
+// ---------------------- Knowledge Graph ----------------------
+// Retrieves what is known about an entity: its type and the relations it is part of
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn query_graph(entity_name: String) -> Option<GraphQueryResult> {
+    query_graph_entity(&entity_name)
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_graph_entities(offset: u64, limit: u64) -> Vec<GraphEntity> {
+    STATE.with(|s| {
+        s.borrow()
+            .stable_graph_entity_data
+            .iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_MEMORY_PAGE_SIZE) as usize)
+            .collect()
+    })
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn clear_knowledge_graph() {
+    STATE.with(|s| {
+        s.borrow_mut().stable_graph_entity_data =
+            StableVec::new(memory::get_stable_graph_entity_vec_memory())
+                .expect("call to get_stable_graph_entity_vec_memory fails");
+        s.borrow_mut().stable_graph_relation_data =
+            StableVec::new(memory::get_stable_graph_relation_vec_memory())
+                .expect("call to get_stable_graph_relation_vec_memory fails");
+        s.borrow_mut().stable_graph_entity_index_data =
+            StableBTreeMap::new(memory::get_stable_graph_entity_index_map_memory());
+        s.borrow_mut().stable_graph_relation_index_data =
+            StableBTreeMap::new(memory::get_stable_graph_relation_index_map_memory());
+    });
+}
+
+// ---------------------- Knowledge Base Document Ingestion ----------------------
+// Uploads a document in one or more parts. Once the last part is received, the document is
+// chunked, embedded and stored in the vector canister in the background.
+// Uploading an existing doc_id again re-ingests it and replaces its previous chunks,
+// superseding an ingestion still in progress.
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn upload_document(
+    doc_id: String,
+    title: String,
+    format: DocumentFormat,
+    content_part: String,
+    is_last_part: bool,
+) -> Result<KnowledgeDocument, String> {
+    // replacing and deleting the chunks of a document needs the memory API
+    if !get_vector_memory_api_enabled() {
+        return Err(
+            "Document ingestion requires the vector canister memory API, enable it first."
+                .to_string(),
+        );
+    }
+
+    let cur_status: Option<DocumentStatus> =
+        get_knowledge_document(doc_id.clone()).map(|doc| doc.status);
+
+    // the first part creates or resets the document
+    if cur_status != Some(DocumentStatus::Uploading) {
+        let now: Timestamp = time();
+        let new_document = KnowledgeDocument {
+            doc_id: doc_id.clone(),
+            title,
+            format,
+            status: DocumentStatus::Uploading,
+            num_chunks: 0,
+            num_chunks_ingested: 0,
+            created_at: now,
+            updated_at: now,
+        };
+
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            state.knowledge_documents.retain(|doc| doc.doc_id != doc_id);
+            state.knowledge_documents.push(new_document);
+            state
+                .document_uploads
+                .retain(|upload| upload.doc_id != doc_id);
+            state.document_uploads.push(DocumentUpload {
+                doc_id: doc_id.clone(),
+                content: String::new(),
+                has_previous_version: cur_status.is_some(),
+            });
+        });
+    }
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let upload = state
+            .document_uploads
+            .iter_mut()
+            .find(|upload| upload.doc_id == doc_id)
+            .unwrap();
+        upload.content.push_str(&content_part);
+    });
+    update_knowledge_document(&doc_id, |_| {});
+
+    if is_last_part {
+        let upload: DocumentUpload = STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            let index = state
+                .document_uploads
+                .iter()
+                .position(|upload| upload.doc_id == doc_id)
+                .unwrap();
+            state.document_uploads.remove(index)
+        });
+
+        let doc_format = get_knowledge_document(doc_id.clone()).unwrap().format;
+        if doc_format == DocumentFormat::Json {
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(&upload.content) {
+                let message = format!("Invalid JSON document: {}", e);
+                update_knowledge_document(&doc_id, |doc| {
+                    doc.status = DocumentStatus::Failed(message.clone())
+                });
+                return Err(message);
+            }
+        }
+
+        update_knowledge_document(&doc_id, |doc| doc.status = DocumentStatus::Ingesting);
+        ic_cdk::spawn(ingest_document(doc_id.clone(), upload));
+    }
+
+    return Ok(get_knowledge_document(doc_id).unwrap());
+}
+
+// Chunks the document, generates embeddings and saves each chunk into vectordb.
+// Any failure marks the document as failed. An ingestion stops once the document is deleted
+// or re-uploaded, which resets its created_at, so it never overwrites the newer status.
+async fn ingest_document(doc_id: String, upload: DocumentUpload) {
+    let created_at: Timestamp = match get_knowledge_document(doc_id.clone()) {
+        Some(doc) => doc.created_at,
+        None => return,
+    };
+
+    let status = match ingest_document_chunks(&doc_id, created_at, upload).await {
+        Ok(()) => DocumentStatus::Ingested,
+        Err(e) => DocumentStatus::Failed(e),
+    };
+    update_ingesting_document(&doc_id, created_at, |doc| doc.status = status);
+}
+
+async fn ingest_document_chunks(
+    doc_id: &str,
+    created_at: Timestamp,
+    upload: DocumentUpload,
+) -> Result<(), String> {
+    // remove chunks saved by a previous ingestion of the same document
+    if upload.has_previous_version {
+        delete_vecdoc_parent(doc_id.to_string()).await?;
+    }
+
+    let chunks: Vec<Chunk> = chunk_content(&upload.content, CHUNK_MAX_TOKENS, CHUNK_OVERLAP_TOKENS);
+    let num_chunks = chunks.len() as u32;
+    update_ingesting_document(doc_id, created_at, |doc| doc.num_chunks = num_chunks);
+
+    for chunk in chunks {
+        let embeddings = generate_embeddings(None, chunk.content.clone()).await?;
+
+        let vec_doc = VecDoc {
+            content: chunk.content,
+            embeddings,
+            namespace: Some(VEC_NAMESPACE_DOCUMENT.to_string()),
+            parent_id: Some(doc_id.to_string()),
+            chunk_index: Some(chunk.index),
+            importance: Some(DOCUMENT_IMPORTANCE),
+        };
+        add_vecdoc(vec_doc).await?;
+
+        let is_current =
+            update_ingesting_document(doc_id, created_at, |doc| doc.num_chunks_ingested += 1);
+        if !is_current {
+            // a deleted document leaves no chunks behind, a re-uploaded one replaces them
+            if get_knowledge_document(doc_id.to_string()).is_none() {
+                delete_vecdoc_parent(doc_id.to_string()).await?;
+            }
+            return Err("Document ingestion was superseded.".to_string());
+        }
+    }
+
+    return Ok(());
+}
+
+// Updates the document only while it is ingested by the ingestion started at created_at
+fn update_ingesting_document(
+    doc_id: &str,
+    created_at: Timestamp,
+    update: impl FnOnce(&mut KnowledgeDocument),
+) -> bool {
+    let is_current: bool = get_knowledge_document(doc_id.to_string())
+        .is_some_and(|doc| doc.created_at == created_at && doc.status == DocumentStatus::Ingesting);
+    if is_current {
+        update_knowledge_document(doc_id, update);
+    }
+    is_current
+}
+
+fn update_knowledge_document(doc_id: &str, update: impl FnOnce(&mut KnowledgeDocument)) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if let Some(doc) = state
+            .knowledge_documents
+            .iter_mut()
+            .find(|doc| doc.doc_id == doc_id)
+        {
+            update(doc);
+            doc.updated_at = time();
+        }
+    });
+}
+
+async fn delete_vecdoc_parent(parent_id: String) -> Result<u64, String> {
+    let (result,): (Result<u64, String>,) =
+        call_vector_memory_api("delete_parent", (parent_id,)).await?;
+
+    return result;
+}
+
+// Retrieves a knowledge base document with its ingestion progress
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_knowledge_document(doc_id: String) -> Option<KnowledgeDocument> {
+    STATE.with(|s| {
+        s.borrow()
+            .knowledge_documents
+            .iter()
+            .find(|doc| doc.doc_id == doc_id)
+            .cloned()
+    })
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn list_knowledge_documents() -> Vec<KnowledgeDocument> {
+    STATE.with(|s| s.borrow().knowledge_documents.clone())
+}
+
+// Deletes a knowledge base document and all its chunks from the vector canister,
+// stopping its ingestion if still in progress
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn delete_knowledge_document(doc_id: String) -> Result<(), String> {
+    let cur_status: Option<DocumentStatus> =
+        get_knowledge_document(doc_id.clone()).map(|doc| doc.status);
+    if cur_status.is_none() {
+        return Err("Document not found.".to_string());
+    }
+
+    delete_vecdoc_parent(doc_id.clone()).await?;
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.knowledge_documents.retain(|doc| doc.doc_id != doc_id);
+        state
+            .document_uploads
+            .retain(|upload| upload.doc_id != doc_id);
+    });
+
+    return Ok(());
+}
+
+// ---------------------- Workflows ----------------------
+// Creates or replaces a workflow of goal templates chained by their dependencies
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn create_workflow(
+    workflow_id: String,
+    name: String,
+    nodes: Vec<WorkflowNode>,
+) -> Result<Workflow, String> {
+    validate_workflow_nodes(&nodes)?;
+
+    let now: Timestamp = time();
+    let created_at: Timestamp = get_workflow(workflow_id.clone())
+        .map(|workflow| workflow.created_at)
+        .unwrap_or(now);
+    let workflow = Workflow {
+        workflow_id: workflow_id.clone(),
+        name,
+        nodes,
+        created_at,
+        updated_at: now,
+    };
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state
+            .workflows
+            .retain(|workflow| workflow.workflow_id != workflow_id);
+        state.workflows.push(workflow.clone());
+    });
+
+    return Ok(workflow);
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_workflow(workflow_id: String) -> Option<Workflow> {
+    STATE.with(|s| {
+        s.borrow()
+            .workflows
+            .iter()
+            .find(|workflow| workflow.workflow_id == workflow_id)
+            .cloned()
+    })
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn list_workflows() -> Vec<Workflow> {
+    STATE.with(|s| s.borrow().workflows.clone())
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn delete_workflow(workflow_id: String) -> Result<(), String> {
+    if get_workflow(workflow_id.clone()).is_none() {
+        return Err("Workflow not found.".to_string());
+    }
+
+    STATE.with(|s| {
+        s.borrow_mut()
+            .workflows
+            .retain(|workflow| workflow.workflow_id != workflow_id)
+    });
+
+    return Ok(());
+}
+
+// Starts a run of a workflow, scheduling the goals of the nodes without dependencies
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn run_workflow(workflow_id: String) -> Result<u64, String> {
+    let workflow: Workflow = match get_workflow(workflow_id.clone()) {
+        Some(workflow) => workflow,
+        None => return Err("Workflow not found.".to_string()),
+    };
+
+    let now: Timestamp = time();
+    let run_id: u64 = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let run_id: u64 = state
+            .workflow_runs
+            .iter()
+            .map(|run| run.run_id + 1)
+            .max()
+            .unwrap_or(0);
+        state.workflow_runs.push(WorkflowRun {
+            run_id,
+            workflow_id,
+            status: WorkflowRunStatus::Running,
+            nodes: workflow
+                .nodes
+                .iter()
+                .map(|node| WorkflowRunNode {
+                    node_id: node.node_id.clone(),
+                    goal_key: None,
+                })
+                .collect(),
+            created_at: now,
+            updated_at: now,
+        });
+        run_id
+    });
+
+    advance_workflow_runs();
+
+    return Ok(run_id);
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_workflow_run(run_id: u64) -> Option<WorkflowRunProgress> {
+    STATE
+        .with(|s| {
+            s.borrow()
+                .workflow_runs
+                .iter()
+                .find(|run| run.run_id == run_id)
+                .cloned()
+        })
+        .map(get_workflow_run_progress)
+}
+
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn list_workflow_runs(workflow_id: String) -> Vec<WorkflowRunProgress> {
+    let runs: Vec<WorkflowRun> = STATE.with(|s| {
+        s.borrow()
+            .workflow_runs
+            .iter()
+            .filter(|run| run.workflow_id == workflow_id)
+            .cloned()
+            .collect()
+    });
+
+    runs.into_iter().map(get_workflow_run_progress).collect()
+}
+
+fn get_workflow_run_progress(run: WorkflowRun) -> WorkflowRunProgress {
+    let nodes: Vec<WorkflowNodeProgress> = run
+        .nodes
+        .into_iter()
+        .map(|run_node| {
+            let goal: Option<Goal> = run_node
+                .goal_key
+                .and_then(|goal_key| STATE.with(|s| s.borrow().stable_goal_data.get(goal_key)));
+            WorkflowNodeProgress {
+                node_id: run_node.node_id,
+                goal_key: run_node.goal_key,
+                status: goal.as_ref().map(|goal| goal.status.clone()),
+                result: goal.and_then(|goal| goal.result),
+            }
+        })
+        .collect();
+
+    WorkflowRunProgress {
+        run_id: run.run_id,
+        workflow_id: run.workflow_id,
+        status: run.status,
+        nodes,
+        created_at: run.created_at,
+        updated_at: run.updated_at,
+    }
+}
+
+// Fails the running workflow run that the goal is a node of
+fn fail_workflow_run_of_goal(goal_key: u64, error: &str) {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        for run in state.workflow_runs.iter_mut() {
+            if run.status != WorkflowRunStatus::Running {
+                continue;
+            }
+            if let Some(run_node) = run
+                .nodes
+                .iter()
+                .find(|run_node| run_node.goal_key == Some(goal_key))
+            {
+                run.status = WorkflowRunStatus::Failed(format!(
+                    "Node {} failed: {}",
+                    run_node.node_id, error
+                ));
+                run.updated_at = time();
+            }
+        }
+    });
+}
+
+// Schedules the goals of workflow nodes whose dependencies are Complete,
+// completes the runs whose goals are all Complete and fails the runs with a timed out goal
+fn advance_workflow_runs() {
+    let runs: Vec<WorkflowRun> = STATE.with(|s| {
+        s.borrow()
+            .workflow_runs
+            .iter()
+            .filter(|run| run.status == WorkflowRunStatus::Running)
+            .cloned()
+            .collect()
+    });
 
-    let (result,): (String,) = ic_cdk::api::call::call(vector_canister, "add", (vec_doc,))
-        .await
-        .expect("call to vector_canister.add failed");
+    let mut is_scheduled = false;
+    for mut run in runs {
+        let workflow: Workflow = match get_workflow(run.workflow_id.clone()) {
+            Some(workflow) => workflow,
+            None => continue,
+        };
 
-    return result;
-}
+        // results of the Complete goals of the run by node id
+        let results: HashMap<String, String> = run
+            .nodes
+            .iter()
+            .filter_map(|run_node| {
+                let goal: Goal = run_node.goal_key.and_then(|goal_key| {
+                    STATE.with(|s| s.borrow().stable_goal_data.get(goal_key))
+                })?;
+                if goal.status != GoalStatus::Complete {
+                    return None;
+                }
+                Some((run_node.node_id.clone(), goal.result.unwrap_or_default()))
+            })
+            .collect();
 
-async fn search_vecdoc(embeddings: Embeddings) -> Option<Vec<PlainDoc>> {
-    let query: VecQuery = VecQuery::Embeddings(embeddings);
-    let vector_canister: Principal = STATE.with(|state| (*state.borrow()).vector_canister.unwrap());
+        let now: Timestamp = time();
+        let timeout: Timestamp = WORKFLOW_NODE_TIMEOUT_SECS * 1_000_000_000;
+        let timed_out_goal_key: Option<u64> = run
+            .nodes
+            .iter()
+            .filter(|run_node| !results.contains_key(&run_node.node_id))
+            .filter_map(|run_node| run_node.goal_key)
+            .find(|goal_key| {
+                STATE
+                    .with(|s| s.borrow().stable_goal_data.get(*goal_key))
+                    .is_some_and(|goal| now.saturating_sub(goal.created_at) >= timeout)
+            });
+        if let Some(goal_key) = timed_out_goal_key {
+            let error = format!(
+                "Goal has not completed within {} hours.",
+                WORKFLOW_NODE_TIMEOUT_SECS / 3600
+            );
+            fail_workflow_run_of_goal(goal_key, &error);
+            continue;
+        }
 
-    let (result,): (Option<Vec<PlainDoc>>,) =
-        ic_cdk::api::call::call(vector_canister, "search", (query, VEC_SEARCH_TOP_K_NN))
-            .await
-            .expect("call to vector_canister.search failed");
+        for node in find_ready_nodes(&workflow.nodes, &run, &results) {
+            let goal_string = render_goal_template(&node.goal_template, &results);
+            let new_goal: Goal = create_goal(goal_string.clone());
+            let goal_key: u64 = push_goal(&new_goal);
+            insert_chat(goal_key, ChatRole::User, goal_string);
+
+            if let Some(run_node) = run
+                .nodes
+                .iter_mut()
+                .find(|run_node| run_node.node_id == node.node_id)
+            {
+                run_node.goal_key = Some(goal_key);
+            }
+            is_scheduled = true;
+        }
 
-    return result;
-}
+        if results.len() == run.nodes.len() {
+            run.status = WorkflowRunStatus::Complete;
+        }
+        run.updated_at = now;
 
-async fn generate_embeddings(content: String) -> Result<Embeddings, String> {
-    let brain_canister: Principal = STATE.with(|state| (*state.borrow()).brain_canister.unwrap());
-    let num_retries: i8 = 0;
-    let (result,): (Result<Embeddings, String>,) = ic_cdk::api::call::call(
-        brain_canister,
-        "generate_embeddings",
-        (content, num_retries),
-    )
-    .await
-    .expect("call to generate_embeddings failed");
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            if let Some(cur_run) = state
+                .workflow_runs
+                .iter_mut()
+                .find(|cur_run| cur_run.run_id == run.run_id)
+            {
+                *cur_run = run;
+            }
+        });
+    }
 
-    return result;
+    if is_scheduled {
+        run_new_goal_async();
+    }
 }
 
-fn get_paymenttransction() -> Vec<PaymentTransaction> {
-    STATE.with(|s| s.borrow().stable_paymenttransaction_data.iter().collect())
+// ---------------------- Goal Schedules ----------------------
+// Creates a schedule that starts a new goal from goal_template every time its trigger fires
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn create_goal_schedule(
+    name: String,
+    trigger: ScheduleTrigger,
+    goal_template: String,
+    max_num_thoughts: Option<u16>,
+) -> Result<GoalSchedule, String> {
+    let now: Timestamp = time();
+    let next_run_at: Timestamp = next_run_at(&trigger, now)?;
+
+    let schedule: GoalSchedule = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let schedule_id: u64 = state
+            .goal_schedules
+            .iter()
+            .map(|schedule| schedule.schedule_id + 1)
+            .max()
+            .unwrap_or(0);
+        let schedule = GoalSchedule {
+            schedule_id,
+            name,
+            trigger,
+            goal_template,
+            max_num_thoughts,
+            is_paused: false,
+            next_run_at,
+            runs: Vec::new(),
+            created_at: now,
+            updated_at: now,
+        };
+        state.goal_schedules.push(schedule.clone());
+        schedule
+    });
+
+    return Ok(schedule);
 }
 
-// Retrieves goal from stable data
 #[query(guard = "assert_owner")]
 #[candid_method(query)]
-fn get_goal(key: u64) -> Option<Goal> {
-    STATE.with(|s| s.borrow().stable_goal_data.get(key))
+fn get_goal_schedule(schedule_id: u64) -> Option<GoalSchedule> {
+    STATE.with(|s| {
+        s.borrow()
+            .goal_schedules
+            .iter()
+            .find(|schedule| schedule.schedule_id == schedule_id)
+            .cloned()
+    })
 }
 
-// Retrieves chathistory from stable data
 #[query(guard = "assert_owner")]
 #[candid_method(query)]
-fn get_chathistory() -> Vec<ChatHistory> {
-    STATE.with(|s| s.borrow().stable_chathistory_data.iter().collect())
+fn list_goal_schedules() -> Vec<GoalSchedule> {
+    STATE.with(|s| s.borrow().goal_schedules.clone())
 }
 
-// Inserts a goal into the stable data Goal Vec and ChatHistory Vec
+// Pauses or resumes a schedule, a resumed schedule next runs at its next trigger time from now
 #[update(guard = "assert_owner")]
 #[candid_method(update)]
-fn insert_goal(goal_string: String) {
+fn pause_goal_schedule(schedule_id: u64, is_paused: bool) -> Result<GoalSchedule, String> {
     let now: Timestamp = time();
-    let new_goal = Goal {
-        goal: goal_string.clone(),
-        status: GoalStatus::Scheduled,
-        created_at: now,
-        updated_at: now,
-        result: None,
-    };
 
     STATE.with(|s| {
-        s.borrow_mut()
-            .stable_goal_data
-            .push(&new_goal)
-            .expect("call to insert_goal failed")
-    });
+        let mut state = s.borrow_mut();
+        let schedule = match state
+            .goal_schedules
+            .iter_mut()
+            .find(|schedule| schedule.schedule_id == schedule_id)
+        {
+            Some(schedule) => schedule,
+            None => return Err("Schedule not found.".to_string()),
+        };
+
+        if schedule.is_paused && !is_paused {
+            schedule.next_run_at = next_run_at(&schedule.trigger, now)?;
+        }
+        schedule.is_paused = is_paused;
+        schedule.updated_at = now;
 
-    insert_chat(ChatRole::User, goal_string.clone());
+        Ok(schedule.clone())
+    })
 }
 
-// Inserts a goal into the stable data Goal Vec and ChatHistory Vec, and clear existing goals
 #[update(guard = "assert_owner")]
 #[candid_method(update)]
-fn start_new_goal(goal_string: String) {
-    let now: Timestamp = time();
-    let new_goal = Goal {
-        goal: goal_string.clone(),
-        status: GoalStatus::Scheduled,
-        created_at: now,
-        updated_at: now,
-        result: None,
-    };
-
-    clear_all_goals();
+fn delete_goal_schedule(schedule_id: u64) -> Result<(), String> {
+    if get_goal_schedule(schedule_id).is_none() {
+        return Err("Schedule not found.".to_string());
+    }
 
     STATE.with(|s| {
         s.borrow_mut()
-            .stable_goal_data
-            .push(&new_goal)
-            .expect("call to insert_goal failed")
+            .goal_schedules
+            .retain(|schedule| schedule.schedule_id != schedule_id)
     });
 
-    insert_chat(ChatRole::User, goal_string.clone());
+    return Ok(());
+}
+
+// Starts the goals of the schedules that are due, called by the schedule timer.
+// Runs missed while the canister was stopped or upgrading are not caught up.
+fn run_due_goal_schedules() {
+    let now: Timestamp = time();
+    let due_schedules: Vec<GoalSchedule> = STATE.with(|s| {
+        s.borrow()
+            .goal_schedules
+            .iter()
+            .filter(|schedule| !schedule.is_paused && schedule.next_run_at <= now)
+            .cloned()
+            .collect()
+    });
+    if due_schedules.is_empty() {
+        return;
+    }
+
+    for schedule in due_schedules {
+        let goal_string = render_schedule_goal(&schedule.goal_template, now);
+        let new_goal = Goal {
+            max_num_thoughts: schedule.max_num_thoughts,
+            ..create_goal(goal_string.clone())
+        };
+        let goal_key: u64 = push_goal(&new_goal);
+        insert_chat(goal_key, ChatRole::User, goal_string);
+
+        ic_cdk::println!(
+            "Schedule {} started goal {}",
+            schedule.schedule_id,
+            goal_key
+        );
+
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            if let Some(cur_schedule) = state
+                .goal_schedules
+                .iter_mut()
+                .find(|cur_schedule| cur_schedule.schedule_id == schedule.schedule_id)
+            {
+                cur_schedule.runs.push(ScheduleRun {
+                    goal_key,
+                    started_at: now,
+                });
+                if cur_schedule.runs.len() > MAX_NUM_SCHEDULE_RUNS {
+                    cur_schedule.runs.remove(0);
+                }
+                // the trigger was validated when the schedule was created
+                cur_schedule.next_run_at =
+                    next_run_at(&cur_schedule.trigger, now).unwrap_or(u64::MAX);
+                cur_schedule.updated_at = now;
+            }
+        });
+    }
 
-    // run new goal in background
     run_new_goal_async();
 }
 
-fn update_goal_status(index: u64, goal: Goal, status: GoalStatus) {
-    let updated_goal: Goal = Goal {
-        status: status,
-        ..goal
-    };
-    STATE.with(|s| s.borrow_mut().stable_goal_data.set(index, &updated_goal));
+// ---------------------- Watchers ----------------------
+// Creates a watcher that polls url every interval_secs and starts a goal when it changes.
+// The first poll only records the content to compare the next polls with.
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn create_watcher(
+    name: String,
+    url: String,
+    source: WatchSource,
+    goal_template: String,
+    interval_secs: u64,
+    min_changed_lines: Option<u32>,
+    max_num_thoughts: Option<u16>,
+) -> Result<Watcher, String> {
+    if interval_secs < MIN_WATCH_INTERVAL_SECS {
+        return Err(format!(
+            "Watcher interval must be at least {} seconds.",
+            MIN_WATCH_INTERVAL_SECS
+        ));
+    }
+    if !url.starts_with("https://") && !url.starts_with("http://") {
+        return Err("Watcher url must start with http:// or https://".to_string());
+    }
+
+    let now: Timestamp = time();
+    let watcher: Watcher = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let watcher_id: u64 = state
+            .watchers
+            .iter()
+            .map(|watcher| watcher.watcher_id + 1)
+            .max()
+            .unwrap_or(0);
+        let watcher = Watcher {
+            watcher_id,
+            name,
+            url,
+            source,
+            goal_template,
+            interval_secs,
+            min_changed_lines: min_changed_lines.unwrap_or(1),
+            max_num_thoughts,
+            is_paused: false,
+            fingerprint: None,
+            next_check_at: now,
+            last_checked_at: None,
+            last_changed_at: None,
+            runs: Vec::new(),
+            created_at: now,
+            updated_at: now,
+        };
+        state.watchers.push(watcher.clone());
+        watcher
+    });
+
+    return Ok(watcher);
 }
 
-// Complete a goal with result, called by controller itself
-fn save_result(key: u64, result: String) {
-    let opt_goal: Option<Goal> = STATE.with(|s| s.borrow().stable_goal_data.get(key));
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn get_watcher(watcher_id: u64) -> Option<Watcher> {
+    STATE.with(|s| {
+        s.borrow()
+            .watchers
+            .iter()
+            .find(|watcher| watcher.watcher_id == watcher_id)
+            .cloned()
+    })
+}
 
-    match opt_goal {
-        Some(my_goal) => {
-            let now: Timestamp = time();
-            let updated_goal: Goal = Goal {
-                result: Some(result),
-                status: GoalStatus::Complete,
-                updated_at: now,
-                ..my_goal
-            };
+#[query(guard = "assert_owner")]
+#[candid_method(query)]
+fn list_watchers() -> Vec<Watcher> {
+    STATE.with(|s| s.borrow().watchers.clone())
+}
 
-            STATE.with(|s| s.borrow_mut().stable_goal_data.set(key, &updated_goal));
-        }
-        None => {
-            ic_cdk::trap("Goal not found.");
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn pause_watcher(watcher_id: u64, is_paused: bool) -> Result<Watcher, String> {
+    let now: Timestamp = time();
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        match state
+            .watchers
+            .iter_mut()
+            .find(|watcher| watcher.watcher_id == watcher_id)
+        {
+            Some(watcher) => {
+                watcher.is_paused = is_paused;
+                watcher.updated_at = now;
+                Ok(watcher.clone())
+            }
+            None => Err("Watcher not found.".to_string()),
         }
-    }
+    })
 }
 
-// Insert chat, called by controller itself
-fn insert_chat(role: ChatRole, content: String) {
-    let now: Timestamp = time();
-    let new_chat = ChatHistory {
-        content: content,
-        role: role,
-        created_at: now,
-    };
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+fn delete_watcher(watcher_id: u64) -> Result<(), String> {
+    if get_watcher(watcher_id).is_none() {
+        return Err("Watcher not found.".to_string());
+    }
 
     STATE.with(|s| {
-        s.borrow_mut()
-            .stable_chathistory_data
-            .push(&new_chat)
-            .expect("call to insert_chat failed")
+        let mut state = s.borrow_mut();
+        state
+            .watchers
+            .retain(|watcher| watcher.watcher_id != watcher_id);
+        state
+            .watcher_contents
+            .retain(|content| content.watcher_id != watcher_id);
     });
-}
 
-fn write_file_and_shutdown(_key: String, text: String) {
-    insert_chat(ChatRole::ArcMind, text);
+    return Ok(());
 }
 
-async fn google(query: String) -> String {
-    let tools_canister: Principal = STATE.with(|state| (*state.borrow()).tools_canister.unwrap());
-    let (result,): (String,) = ic_cdk::api::call::call(tools_canister, "google", (query,))
-        .await
-        .expect("call to google failed");
+// Polls the watchers that are due, called by the watcher timer
+async fn check_due_watchers() {
+    let now: Timestamp = time();
+    let due_watchers: Vec<Watcher> = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let mut due_watchers: Vec<Watcher> = Vec::new();
+        for watcher in state.watchers.iter_mut() {
+            if !watcher.is_paused && watcher.next_check_at <= now {
+                // set before polling so that a failed poll waits for the next interval
+                watcher.next_check_at = watcher
+                    .interval_secs
+                    .checked_mul(1_000_000_000)
+                    .and_then(|nanos| now.checked_add(nanos))
+                    .unwrap_or(u64::MAX);
+                due_watchers.push(watcher.clone());
+            }
+        }
+        due_watchers
+    });
 
-    return result;
+    for watcher in due_watchers {
+        match poll_watcher_url(&watcher).await {
+            Ok(content) => check_watcher_content(watcher, content),
+            Err(e) => ic_cdk::println!("Failed to poll watcher {}: {}", watcher.watcher_id, e),
+        }
+    }
 }
 
-async fn browse_website(url: String, _question: String) -> String {
+// Web pages are polled as text, feeds as they are to keep their items.
+// A failed poll is an error, so that it is not taken as a change of the content.
+async fn poll_watcher_url(watcher: &Watcher) -> Result<String, String> {
     let tools_canister: Principal = STATE.with(|state| (*state.borrow()).tools_canister.unwrap());
-    let (result,): (String,) = ic_cdk::api::call::call(tools_canister, "browse_website", (url,))
-        .await
-        .expect("call to browse_website failed");
+    let method = match watcher.source {
+        WatchSource::WebPage => "browse_website_with_usage",
+        WatchSource::Feed => "fetch_website_with_usage",
+    };
 
-    return result;
+    let (result,): (ToolResult,) =
+        ic_cdk::api::call::call(tools_canister, method, (watcher.url.clone(),))
+            .await
+            .map_err(|(code, message)| {
+                format!("call to {} failed: {:?} {}", method, code, message)
+            })?;
+    record_usage(None, CostSource::Tools, &result.usage);
+
+    if is_tool_error(&result.content) {
+        return Err(result.content);
+    }
+
+    return Ok(result.content);
 }
 
-#[update(guard = "assert_owner")]
-#[candid_method(update)]
-fn clear_all_goals() {
-    // clear and reinit stable_chathistory_data and stable_goal_data
+// Compares the polled content with the last content, and starts a goal on meaningful changes
+fn check_watcher_content(watcher: Watcher, content: String) {
+    let now: Timestamp = time();
+    let new_lines: Vec<String> = extract_watch_lines(&content, &watcher.source);
+    let fingerprint: String = fingerprint_lines(&new_lines);
+
+    let old_lines: Vec<String> = STATE.with(|s| {
+        s.borrow()
+            .watcher_contents
+            .iter()
+            .find(|content| content.watcher_id == watcher.watcher_id)
+            .map(|content| content.lines.clone())
+            .unwrap_or_default()
+    });
+    let diff = diff_lines(&old_lines, &new_lines);
+
+    let is_changed = watcher.fingerprint.is_some()
+        && watcher.fingerprint.as_ref() != Some(&fingerprint)
+        && diff.num_changed_lines() >= watcher.min_changed_lines as usize;
+
+    let mut opt_goal_key: Option<u64> = None;
+    if is_changed {
+        let goal_string = render_watcher_goal(&watcher.goal_template, &watcher.url, &diff);
+        let new_goal = Goal {
+            max_num_thoughts: watcher.max_num_thoughts,
+            ..create_goal(goal_string.clone())
+        };
+        let goal_key: u64 = push_goal(&new_goal);
+        insert_chat(goal_key, ChatRole::User, goal_string);
+        opt_goal_key = Some(goal_key);
+
+        ic_cdk::println!("Watcher {} started goal {}", watcher.watcher_id, goal_key);
+    }
+
+    // small changes below min_changed_lines accumulate until they add up
+    let is_baseline_updated = watcher.fingerprint.is_none() || is_changed;
+
     STATE.with(|s| {
-        s.borrow_mut().stable_chathistory_data =
-            StableVec::new(memory::get_stable_chathistory_vec_memory())
-                .expect("call to get_stable_goal_vec_memory fails");
-        s.borrow_mut().stable_goal_data = StableVec::new(memory::get_stable_goal_vec_memory())
-            .expect("call to get_stable_goal_vec_memory fails")
+        let mut state = s.borrow_mut();
+        if let Some(cur_watcher) = state
+            .watchers
+            .iter_mut()
+            .find(|cur_watcher| cur_watcher.watcher_id == watcher.watcher_id)
+        {
+            cur_watcher.last_checked_at = Some(now);
+            if is_baseline_updated {
+                cur_watcher.fingerprint = Some(fingerprint);
+            }
+            if let Some(goal_key) = opt_goal_key {
+                cur_watcher.last_changed_at = Some(now);
+                cur_watcher.runs.push(ScheduleRun {
+                    goal_key,
+                    started_at: now,
+                });
+                if cur_watcher.runs.len() > MAX_NUM_SCHEDULE_RUNS {
+                    cur_watcher.runs.remove(0);
+                }
+            }
+        }
+
+        if is_baseline_updated {
+            state
+                .watcher_contents
+                .retain(|content| content.watcher_id != watcher.watcher_id);
+            state.watcher_contents.push(WatcherContent {
+                watcher_id: watcher.watcher_id,
+                lines: new_lines,
+            });
+        }
     });
+
+    if opt_goal_key.is_some() {
+        run_new_goal_async();
+    }
 }
 
 // ---------------------- Supporting Functions ----------------------
@@ -877,14 +4392,42 @@ fn init(
             max_num_thoughts_allowed: DEFAULT_MAX_NUM_THOUGHTS_ALLOWED as u64,
             num_thoughts_processed: 0,
             billing_key: billing_key,
+            is_vector_memory_api_enabled: false,
+            hybrid_search_weights: None,
+            retrieval_weights: None,
+            memory_fade_policy: None,
+            knowledge_documents: Vec::new(),
+            document_uploads: Vec::new(),
+            workflows: Vec::new(),
+            workflow_runs: Vec::new(),
+            goal_schedules: Vec::new(),
+            watchers: Vec::new(),
+            watcher_contents: Vec::new(),
+            dry_run_fixtures: Vec::new(),
+            period_costs: Vec::new(),
+            cost_prices: None,
+            model_routes: Vec::new(),
+            model_escalation: None,
             stable_goal_data: init_stable_goal_data(),
             stable_chathistory_data: init_stable_chathistory_data(),
             stable_paymenttransaction_data: init_stable_paymenttransaction_data(),
+            stable_embeddings_cache_data: init_stable_embeddings_cache_data(),
+            stable_embeddings_cache_usage_data: init_stable_embeddings_cache_usage_data(),
+            stable_graph_entity_data: init_stable_graph_entity_data(),
+            stable_graph_relation_data: init_stable_graph_relation_data(),
+            stable_graph_entity_index_data: init_stable_graph_entity_index_data(),
+            stable_graph_relation_index_data: init_stable_graph_relation_index_data(),
+            stable_step_trace_data: init_stable_step_trace_data(),
+            stable_step_trace_index_data: init_stable_step_trace_index_data(),
         };
     });
 
     // Start the periodic tasks
     start_cycles_check_timer(CYCLES_BALANCE_CHECK_MIN_INTERVAL_SECS);
+    start_memory_fade_timer(MEMORY_FADE_CHECK_INTERVAL_SECS);
+    start_schedule_timer(SCHEDULE_CHECK_INTERVAL_SECS);
+    start_watcher_timer(WATCHER_CHECK_INTERVAL_SECS);
+    start_agent_timeout_timer(AGENT_TIMEOUT_CHECK_INTERVAL_SECS);
 }
 
 #[query]
@@ -972,6 +4515,49 @@ pub fn get_browse_website_gpt_model() -> Option<String> {
     STATE.with(|state| (*state.borrow()).browse_website_gpt_model.clone())
 }
 
+// Enable once the vector canister implements the memory API beyond add and search
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+pub fn update_vector_memory_api_enabled(is_enabled: bool) {
+    STATE.with(|state| {
+        state.borrow_mut().is_vector_memory_api_enabled = is_enabled;
+    });
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_vector_memory_api_enabled() -> bool {
+    STATE.with(|state| (*state.borrow()).is_vector_memory_api_enabled)
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+pub fn update_hybrid_search_weights(new_weights: Option<HybridSearchWeights>) {
+    STATE.with(|state| {
+        state.borrow_mut().hybrid_search_weights = new_weights;
+    });
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_hybrid_search_weights() -> Option<HybridSearchWeights> {
+    STATE.with(|state| (*state.borrow()).hybrid_search_weights.clone())
+}
+
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+pub fn update_retrieval_weights(new_weights: Option<RetrievalWeights>) {
+    STATE.with(|state| {
+        state.borrow_mut().retrieval_weights = new_weights;
+    });
+}
+
+#[query]
+#[candid_method(query)]
+pub fn get_retrieval_weights() -> Option<RetrievalWeights> {
+    STATE.with(|state| (*state.borrow()).retrieval_weights.clone())
+}
+
 #[update(guard = "assert_owner")]
 #[candid_method(update)]
 pub fn toggle_pause_cof() {
@@ -1208,6 +4794,10 @@ fn post_upgrade(
     // Update browse_website_gpt_model
     update_browse_website_gpt_model(browse_website_gpt_model);
 
+    index_embeddings_cache_usage();
+    index_knowledge_graph();
+    index_step_traces();
+
     // log update of battery_canister
     ic_cdk::println!(
         "Controller canisters: post_upgrade: battery_canister: {:?}",
@@ -1216,6 +4806,10 @@ fn post_upgrade(
 
     // Start the periodic tasks
     start_cycles_check_timer(CYCLES_BALANCE_CHECK_MIN_INTERVAL_SECS);
+    start_memory_fade_timer(MEMORY_FADE_CHECK_INTERVAL_SECS);
+    start_schedule_timer(SCHEDULE_CHECK_INTERVAL_SECS);
+    start_watcher_timer(WATCHER_CHECK_INTERVAL_SECS);
+    start_agent_timeout_timer(AGENT_TIMEOUT_CHECK_INTERVAL_SECS);
 }
 
 fn run_new_goal_async() {
@@ -1237,6 +4831,51 @@ fn start_cycles_check_timer(secs: u64) {
     TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().push(timer_id));
 }
 
+fn start_memory_fade_timer(secs: u64) {
+    let secs = Duration::from_secs(secs);
+    ic_cdk::println!("Controller canister: fading long term memories with {secs:?} interval...");
+
+    let timer_id = ic_cdk_timers::set_timer_interval(secs, || ic_cdk::spawn(fade_memories()));
+
+    // Add the timer ID to the global vector.
+    TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().push(timer_id));
+}
+
+fn start_schedule_timer(secs: u64) {
+    let secs = Duration::from_secs(secs);
+    ic_cdk::println!("Controller canister: checking goal schedules with {secs:?} interval...");
+
+    let timer_id = ic_cdk_timers::set_timer_interval(secs, run_due_goal_schedules);
+
+    // Add the timer ID to the global vector.
+    TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().push(timer_id));
+}
+
+fn start_watcher_timer(secs: u64) {
+    let secs = Duration::from_secs(secs);
+    ic_cdk::println!("Controller canister: checking watchers with {secs:?} interval...");
+
+    let timer_id = ic_cdk_timers::set_timer_interval(secs, || ic_cdk::spawn(check_due_watchers()));
+
+    // Add the timer ID to the global vector.
+    TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().push(timer_id));
+}
+
+fn start_agent_timeout_timer(secs: u64) {
+    let secs = Duration::from_secs(secs);
+    ic_cdk::println!(
+        "Controller canister: checking agent and workflow timeouts with {secs:?} interval..."
+    );
+
+    let timer_id = ic_cdk_timers::set_timer_interval(secs, || {
+        fail_timed_out_child_goals();
+        advance_workflow_runs();
+    });
+
+    // Add the timer ID to the global vector.
+    TIMER_IDS.with(|timer_ids| timer_ids.borrow_mut().push(timer_id));
+}
+
 // ---------------------- Cycles Usage Tracking  --------------------------------------
 /// Tracks the amount of cycles used for the periodic task.
 fn track_cycles_used() {
@@ -1251,7 +4890,14 @@ fn track_cycles_used() {
 // ---------------------- Candid declarations did file generator ----------------------
 #[cfg(test)]
 mod tests {
-    use crate::datatype::{ChatHistory, Goal};
+    use crate::datatype::{
+        ChatHistory, CommandTraceStats, Cost, CostEstimate, CostPrices, CostReport, DebugStep,
+        DocumentFormat, DryRunAction, DryRunFixture, Goal, GoalBudget, GoalGuidance, GoalSchedule,
+        GraphEntity, GraphQueryResult, HybridSearchWeights, KnowledgeDocument, MemoryDoc,
+        MemoryFadePolicy, MemoryPage, ModelEscalation, ModelRoute, RetrievalWeights,
+        ScheduleTrigger, StepKind, StepTrace, Subtask, Timestamp, WatchSource, Watcher, Workflow,
+        WorkflowNode, WorkflowRunProgress,
+    };
     use candid::{export_service, Principal};
 
     #[test]
@@ -10,6 +10,14 @@ const UPGRADES: MemoryId = MemoryId::new(0);
 const STABLE_GOAL_VEC: MemoryId = MemoryId::new(1);
 const STABLE_CHATHISTORY_VEC: MemoryId = MemoryId::new(2);
 const STABLE_PAYMENTTRANSACTION_VEC: MemoryId = MemoryId::new(3);
+const STABLE_EMBEDDINGS_CACHE_MAP: MemoryId = MemoryId::new(4);
+const STABLE_GRAPH_ENTITY_VEC: MemoryId = MemoryId::new(5);
+const STABLE_GRAPH_RELATION_VEC: MemoryId = MemoryId::new(6);
+const STABLE_STEP_TRACE_VEC: MemoryId = MemoryId::new(7);
+const STABLE_EMBEDDINGS_CACHE_USAGE_MAP: MemoryId = MemoryId::new(8);
+const STABLE_GRAPH_ENTITY_INDEX_MAP: MemoryId = MemoryId::new(9);
+const STABLE_GRAPH_RELATION_INDEX_MAP: MemoryId = MemoryId::new(10);
+const STABLE_STEP_TRACE_INDEX_MAP: MemoryId = MemoryId::new(11);
 
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -35,3 +43,35 @@ pub fn get_stable_chathistory_vec_memory() -> Memory {
 pub fn get_stable_paymenttransaction_vec_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_PAYMENTTRANSACTION_VEC))
 }
+
+pub fn get_stable_embeddings_cache_map_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_EMBEDDINGS_CACHE_MAP))
+}
+
+pub fn get_stable_graph_entity_vec_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_GRAPH_ENTITY_VEC))
+}
+
+pub fn get_stable_graph_relation_vec_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_GRAPH_RELATION_VEC))
+}
+
+pub fn get_stable_step_trace_vec_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_STEP_TRACE_VEC))
+}
+
+pub fn get_stable_embeddings_cache_usage_map_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_EMBEDDINGS_CACHE_USAGE_MAP))
+}
+
+pub fn get_stable_graph_entity_index_map_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_GRAPH_ENTITY_INDEX_MAP))
+}
+
+pub fn get_stable_graph_relation_index_map_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_GRAPH_RELATION_INDEX_MAP))
+}
+
+pub fn get_stable_step_trace_index_map_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_STEP_TRACE_INDEX_MAP))
+}
@@ -0,0 +1,92 @@
+use serde_json::Value;
+
+use crate::datatype::{Subtask, SubtaskStatus};
+
+pub const MAX_NUM_SUBTASKS: usize = 20;
+
+// Subtasks are given either as a JSON array or as a newline separated list
+pub fn parse_subtasks(value: &Value) -> Option<Vec<Subtask>> {
+    let descriptions: Vec<String> = match value {
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|item| item.as_str())
+            .map(|item| item.to_string())
+            .collect(),
+        Value::String(text) => text.lines().map(|line| line.to_string()).collect(),
+        _ => return None,
+    };
+
+    let subtasks: Vec<Subtask> = descriptions
+        .iter()
+        .map(|description| description.trim().trim_start_matches(['-', '*']).trim())
+        .filter(|description| !description.is_empty())
+        .take(MAX_NUM_SUBTASKS)
+        .map(|description| Subtask {
+            description: description.to_string(),
+            status: SubtaskStatus::Pending,
+        })
+        .collect();
+
+    if subtasks.is_empty() {
+        None
+    } else {
+        Some(subtasks)
+    }
+}
+
+pub fn parse_subtask_status(status: &str) -> Option<SubtaskStatus> {
+    match status.trim().to_lowercase().as_str() {
+        "pending" => Some(SubtaskStatus::Pending),
+        "in_progress" => Some(SubtaskStatus::InProgress),
+        "done" => Some(SubtaskStatus::Done),
+        "skipped" => Some(SubtaskStatus::Skipped),
+        _ => None,
+    }
+}
+
+fn format_subtask_status(status: &SubtaskStatus) -> &'static str {
+    match status {
+        SubtaskStatus::Pending => "pending",
+        SubtaskStatus::InProgress => "in_progress",
+        SubtaskStatus::Done => "done",
+        SubtaskStatus::Skipped => "skipped",
+    }
+}
+
+// Renders the plan as a numbered list for the chain of thoughts prompt
+pub fn format_plan(plan: &Option<Vec<Subtask>>) -> String {
+    match plan {
+        Some(subtasks) => subtasks
+            .iter()
+            .enumerate()
+            .map(|(i, subtask)| {
+                format!(
+                    "{}. [{}] {}",
+                    i + 1,
+                    format_subtask_status(&subtask.status),
+                    subtask.description
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+        None => "No plan yet. Break the goal down into subtasks with \"set_plan\".".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_plan, parse_subtasks};
+    use serde_json::json;
+
+    #[test]
+    fn parses_and_formats_plan() {
+        let plan = parse_subtasks(&json!("- search news\n\n- summarise news"));
+
+        assert_eq!(
+            format_plan(&plan),
+            "1. [pending] search news\n2. [pending] summarise news"
+        );
+        assert_eq!(parse_subtasks(&json!(["a", "b", "c"])).unwrap().len(), 3);
+        assert!(parse_subtasks(&json!([])).is_none());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::datatype::{PlainDoc, RetrievalWeights, Timestamp};
+
+pub const DEFAULT_MEMORY_IMPORTANCE: f32 = 0.5;
+// recency decays by this factor for every hour since the memory was last accessed
+const RECENCY_HOURLY_DECAY: f32 = 0.995;
+const NANOS_PER_HOUR: u64 = 60 * 60 * 1000 * 1000 * 1000;
+
+pub fn default_retrieval_weights() -> RetrievalWeights {
+    RetrievalWeights {
+        similarity: 1.0,
+        recency: 1.0,
+        importance: 1.0,
+    }
+}
+
+pub fn recency_score(last_accessed_at: Timestamp, now: Timestamp) -> f32 {
+    let hours = now.saturating_sub(last_accessed_at) / NANOS_PER_HOUR;
+    RECENCY_HOURLY_DECAY.powf(hours as f32)
+}
+
+// How well a memory is retained: its importance faded by the time since it was last accessed
+pub fn retention_score(
+    importance: Option<f32>,
+    last_accessed_at: Option<Timestamp>,
+    created_at: Timestamp,
+    now: Timestamp,
+) -> f32 {
+    let importance = importance.unwrap_or(DEFAULT_MEMORY_IMPORTANCE);
+    importance * recency_score(last_accessed_at.unwrap_or(created_at), now)
+}
+
+// Parses the 1 - 10 rating answered by the LLM into 0.0 - 1.0
+pub fn parse_importance(answer: &str) -> f32 {
+    let rating: Option<u32> = answer
+        .split(|c: char| !c.is_ascii_digit())
+        .find(|s| !s.is_empty())
+        .and_then(|s| s.parse().ok());
+
+    match rating {
+        Some(rating) => rating.clamp(1, 10) as f32 / 10.0,
+        None => DEFAULT_MEMORY_IMPORTANCE,
+    }
+}
+
+/*
+ * Ranks search results by a weighted sum of similarity, recency and importance,
+ * and keeps the top_k. Results without a similarity score are scored by their
+ * position in the search results.
+ */
+pub fn rank_memories(
+    docs: Vec<PlainDoc>,
+    weights: &RetrievalWeights,
+    now: Timestamp,
+    top_k: usize,
+) -> Vec<PlainDoc> {
+    let num_docs = docs.len() as f32;
+    let mut scored_docs: Vec<(f32, PlainDoc)> = docs
+        .into_iter()
+        .enumerate()
+        .map(|(i, doc)| {
+            let similarity = doc.score.unwrap_or(1.0 - i as f32 / num_docs);
+            let recency = match doc.last_accessed_at.or(doc.created_at) {
+                Some(last_accessed_at) => recency_score(last_accessed_at, now),
+                None => 1.0,
+            };
+            let importance = doc.importance.unwrap_or(DEFAULT_MEMORY_IMPORTANCE);
+
+            let score = weights.similarity * similarity
+                + weights.recency * recency
+                + weights.importance * importance;
+            (score, doc)
+        })
+        .collect();
+
+    scored_docs.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored_docs
+        .into_iter()
+        .take(top_k)
+        .map(|(_, doc)| doc)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_importance, rank_memories, NANOS_PER_HOUR};
+    use crate::datatype::{PlainDoc, RetrievalWeights};
+
+    fn create_doc(id: u64, score: f32, importance: f32, last_accessed_at: u64) -> PlainDoc {
+        PlainDoc {
+            id: Some(id),
+            content: format!("memory {}", id),
+            parent_id: None,
+            chunk_index: None,
+            score: Some(score),
+            importance: Some(importance),
+            created_at: Some(0),
+            last_accessed_at: Some(last_accessed_at),
+        }
+    }
+
+    #[test]
+    fn ranks_by_weighted_score() {
+        let now = 1000 * NANOS_PER_HOUR;
+        let docs = vec![
+            create_doc(1, 0.9, 0.1, 0),
+            create_doc(2, 0.8, 0.9, now),
+            create_doc(3, 0.1, 0.1, 0),
+        ];
+        let weights = RetrievalWeights {
+            similarity: 1.0,
+            recency: 1.0,
+            importance: 1.0,
+        };
+
+        let ranked = rank_memories(docs, &weights, now, 2);
+
+        let ids: Vec<u64> = ranked.iter().map(|doc| doc.id.unwrap()).collect();
+        assert_eq!(ids, vec![2, 1]);
+    }
+
+    #[test]
+    fn parses_importance_rating() {
+        assert_eq!(parse_importance("7"), 0.7);
+        assert_eq!(parse_importance("Importance: 10/10"), 1.0);
+        assert_eq!(parse_importance("42"), 1.0);
+        assert_eq!(parse_importance("not sure"), 0.5);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::datatype::{
+    ModelEscalation, ModelRoute, StepKind, StepOutcome, StepTrace, PROMPT_CMD_START_AGENT,
+};
+
+pub const MIN_MODEL_TEMPERATURE: f32 = 0.0;
+pub const MAX_MODEL_TEMPERATURE: f32 = 2.0;
+
+pub fn validate_model_route(route: &ModelRoute) -> Result<(), String> {
+    if route
+        .model
+        .as_ref()
+        .is_some_and(|model| model.trim().is_empty())
+    {
+        return Err("Model name is empty.".to_string());
+    }
+    if route.temperature.is_some_and(|temperature| {
+        !(MIN_MODEL_TEMPERATURE..=MAX_MODEL_TEMPERATURE).contains(&temperature)
+    }) {
+        return Err(format!(
+            "Temperature must be between {} and {}.",
+            MIN_MODEL_TEMPERATURE, MAX_MODEL_TEMPERATURE
+        ));
+    }
+
+    Ok(())
+}
+
+// The configured route of the step kind, or the brain default model and temperature
+pub fn find_model_route(routes: &[ModelRoute], step_kind: StepKind) -> ModelRoute {
+    routes
+        .iter()
+        .find(|route| route.step_kind == step_kind)
+        .cloned()
+        .unwrap_or(ModelRoute {
+            step_kind,
+            model: None,
+            temperature: None,
+        })
+}
+
+// Number of recoveries in a row at the end of the steps of a goal.
+// The start_agent steps asking for the next command after each recovery are skipped.
+pub fn count_trailing_recoveries(traces: &[StepTrace]) -> u16 {
+    traces
+        .iter()
+        .rev()
+        .filter(|trace| {
+            !(trace.outcome == StepOutcome::Ok
+                && trace.command.as_deref() == Some(PROMPT_CMD_START_AGENT))
+        })
+        .take_while(|trace| trace.outcome == StepOutcome::Recovery)
+        .count() as u16
+}
+
+// Main reasoning steps move to the stronger model after repeated recoveries, until a valid command
+pub fn escalate_model_route(
+    route: ModelRoute,
+    escalation: Option<&ModelEscalation>,
+    num_trailing_recoveries: u16,
+) -> ModelRoute {
+    match escalation {
+        Some(escalation)
+            if route.step_kind == StepKind::Main
+                && num_trailing_recoveries >= escalation.after_num_recoveries =>
+        {
+            ModelRoute {
+                model: Some(escalation.model.clone()),
+                temperature: escalation.temperature.or(route.temperature),
+                ..route
+            }
+        }
+        _ => route,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_trailing_recoveries, escalate_model_route, find_model_route};
+    use crate::datatype::{ModelEscalation, ModelRoute, StepKind, StepOutcome, StepTrace};
+
+    fn create_trace(command: Option<&str>, outcome: StepOutcome) -> StepTrace {
+        StepTrace {
+            goal_key: 0,
+            step: 0,
+            command: command.map(|c| c.to_string()),
+            args: String::new(),
+            result_size: 0,
+            model: None,
+            latency_ns: 0,
+            cycles_used: 0,
+            outcome,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn routes_and_escalates_main_steps() {
+        let routes = vec![ModelRoute {
+            step_kind: StepKind::Main,
+            model: Some("gpt-4o-mini".to_string()),
+            temperature: Some(0.2),
+        }];
+        let escalation = ModelEscalation {
+            model: "gpt-4o".to_string(),
+            temperature: None,
+            after_num_recoveries: 2,
+        };
+
+        let reflection_route = find_model_route(&routes, StepKind::Reflection);
+        assert_eq!(reflection_route.model, None);
+
+        let traces = vec![
+            create_trace(Some("google"), StepOutcome::Ok),
+            create_trace(None, StepOutcome::Recovery),
+            create_trace(Some("start_agent"), StepOutcome::Ok),
+            create_trace(None, StepOutcome::Recovery),
+            create_trace(Some("start_agent"), StepOutcome::Ok),
+        ];
+        let num_recoveries = count_trailing_recoveries(&traces);
+        assert_eq!(num_recoveries, 2);
+
+        let main_route = find_model_route(&routes, StepKind::Main);
+        let escalated_route = escalate_model_route(main_route, Some(&escalation), num_recoveries);
+        assert_eq!(escalated_route.model, Some("gpt-4o".to_string()));
+        assert_eq!(escalated_route.temperature, Some(0.2));
+
+        let main_route = find_model_route(&routes, StepKind::Main);
+        let route = escalate_model_route(main_route, Some(&escalation), 1);
+        assert_eq!(route.model, Some("gpt-4o-mini".to_string()));
+    }
+}
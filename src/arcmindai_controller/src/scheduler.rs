@@ -0,0 +1,246 @@
+use time::{Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, Time};
+
+use crate::datatype::{ScheduleTrigger, Timestamp};
+
+pub const MIN_SCHEDULE_INTERVAL_SECS: u64 = 60;
+// 1 year
+pub const MAX_SCHEDULE_INTERVAL_SECS: u64 = 60 * 60 * 24 * 366;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// cron expressions that match no date within this many days are rejected e.g. "0 0 31 2 *"
+const MAX_CRON_SEARCH_DAYS: i64 = 4 * 366;
+
+// Allowed values of each cron field as a bit mask
+pub struct CronExpression {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    is_day_of_month_restricted: bool,
+    is_day_of_week_restricted: bool,
+}
+
+// Parses one field made of comma separated "*", "a", "a-b" with an optional "/step"
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask: u64 = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .map_err(|_| format!("Invalid cron step: {}", part))?,
+            ),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("Invalid cron step: {}", part));
+        }
+
+        let (from, to) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (
+                    from.parse::<u32>()
+                        .map_err(|_| format!("Invalid cron value: {}", part))?,
+                    to.parse::<u32>()
+                        .map_err(|_| format!("Invalid cron value: {}", part))?,
+                ),
+                None => {
+                    let value = range
+                        .parse::<u32>()
+                        .map_err(|_| format!("Invalid cron value: {}", part))?;
+                    (value, value)
+                }
+            },
+        };
+        if from < min || to > max || from > to {
+            return Err(format!("Cron value out of range: {}", part));
+        }
+
+        for value in (from..=to).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+pub fn parse_cron(expression: &str) -> Result<CronExpression, String> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(
+            "Cron expression must have 5 fields: minute hour day_of_month month day_of_week"
+                .to_string(),
+        );
+    }
+
+    let mut days_of_week = parse_cron_field(fields[4], 0, 7)?;
+    // both 0 and 7 are Sunday
+    if days_of_week & (1 << 7) != 0 {
+        days_of_week |= 1;
+    }
+
+    Ok(CronExpression {
+        minutes: parse_cron_field(fields[0], 0, 59)?,
+        hours: parse_cron_field(fields[1], 0, 23)?,
+        days_of_month: parse_cron_field(fields[2], 1, 31)?,
+        months: parse_cron_field(fields[3], 1, 12)?,
+        days_of_week,
+        is_day_of_month_restricted: !fields[2].starts_with('*'),
+        is_day_of_week_restricted: !fields[4].starts_with('*'),
+    })
+}
+
+fn is_set(mask: u64, value: u8) -> bool {
+    mask & (1 << value) != 0
+}
+
+impl CronExpression {
+    // as in cron, a day matches either restricted day field when both are restricted
+    fn is_day_match(&self, date: Date) -> bool {
+        let is_dom_match = is_set(self.days_of_month, date.day());
+        let is_dow_match = is_set(self.days_of_week, date.weekday().number_days_from_sunday());
+
+        match (
+            self.is_day_of_month_restricted,
+            self.is_day_of_week_restricted,
+        ) {
+            (true, true) => is_dom_match || is_dow_match,
+            (true, false) => is_dom_match,
+            (false, true) => is_dow_match,
+            (false, false) => true,
+        }
+    }
+
+    // The first minute matching the expression strictly after the given time
+    pub fn next_after(&self, after: OffsetDateTime) -> Option<OffsetDateTime> {
+        let limit = after + Duration::days(MAX_CRON_SEARCH_DAYS);
+        let mut t =
+            after.replace_second(0).ok()?.replace_nanosecond(0).ok()? + Duration::minutes(1);
+
+        while t < limit {
+            if !is_set(self.months, u8::from(t.month())) {
+                let (year, month) = match t.month() {
+                    Month::December => (t.year() + 1, Month::January),
+                    month => (t.year(), month.next()),
+                };
+                let date = Date::from_calendar_date(year, month, 1).ok()?;
+                t = PrimitiveDateTime::new(date, Time::MIDNIGHT).assume_utc();
+                continue;
+            }
+            if !self.is_day_match(t.date()) {
+                t = PrimitiveDateTime::new(t.date().next_day()?, Time::MIDNIGHT).assume_utc();
+                continue;
+            }
+            if !is_set(self.hours, t.hour()) {
+                t = t.replace_minute(0).ok()? + Duration::hours(1);
+                continue;
+            }
+            if !is_set(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+                continue;
+            }
+            return Some(t);
+        }
+
+        None
+    }
+}
+
+// Checks a trigger and returns the time of its first run after now
+pub fn next_run_at(trigger: &ScheduleTrigger, now: Timestamp) -> Result<Timestamp, String> {
+    match trigger {
+        ScheduleTrigger::Interval(secs) => {
+            if *secs < MIN_SCHEDULE_INTERVAL_SECS {
+                return Err(format!(
+                    "Schedule interval must be at least {} seconds.",
+                    MIN_SCHEDULE_INTERVAL_SECS
+                ));
+            }
+            if *secs > MAX_SCHEDULE_INTERVAL_SECS {
+                return Err(format!(
+                    "Schedule interval must be at most {} seconds.",
+                    MAX_SCHEDULE_INTERVAL_SECS
+                ));
+            }
+            secs.checked_mul(NANOS_PER_SEC)
+                .and_then(|nanos| now.checked_add(nanos))
+                .ok_or("Schedule interval is too long.".to_string())
+        }
+        ScheduleTrigger::Cron(expression) => {
+            let cron = parse_cron(expression)?;
+            let now_dt = OffsetDateTime::from_unix_timestamp_nanos(now as i128)
+                .map_err(|e| e.to_string())?;
+            match cron.next_after(now_dt) {
+                Some(next_dt) => Ok(next_dt.unix_timestamp_nanos() as Timestamp),
+                None => Err(format!("Cron expression never matches: {}", expression)),
+            }
+        }
+    }
+}
+
+pub fn render_schedule_goal(template: &str, now: Timestamp) -> String {
+    let date = OffsetDateTime::from_unix_timestamp_nanos(now as i128)
+        .map(|now_dt| now_dt.date().to_string())
+        .unwrap_or_default();
+    template.replace("{{date}}", &date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_run_at, parse_cron, render_schedule_goal, NANOS_PER_SEC};
+    use crate::datatype::ScheduleTrigger;
+    use time::macros::datetime;
+
+    fn to_nanos(dt: time::OffsetDateTime) -> u64 {
+        dt.unix_timestamp_nanos() as u64
+    }
+
+    #[test]
+    fn finds_next_cron_run() {
+        // Wednesday
+        let now = to_nanos(datetime!(2024-01-10 10:30 UTC));
+
+        let every_monday = ScheduleTrigger::Cron("0 9 * * 1".to_string());
+        assert_eq!(
+            next_run_at(&every_monday, now),
+            Ok(to_nanos(datetime!(2024-01-15 09:00 UTC)))
+        );
+
+        let every_15_min = ScheduleTrigger::Cron("*/15 * * * *".to_string());
+        assert_eq!(
+            next_run_at(&every_15_min, now),
+            Ok(to_nanos(datetime!(2024-01-10 10:45 UTC)))
+        );
+
+        let new_year = ScheduleTrigger::Cron("0 0 1 1 *".to_string());
+        assert_eq!(
+            next_run_at(&new_year, now),
+            Ok(to_nanos(datetime!(2025-01-01 00:00 UTC)))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_triggers() {
+        assert!(parse_cron("0 9 * *").is_err());
+        assert!(parse_cron("61 * * * *").is_err());
+        assert!(next_run_at(&ScheduleTrigger::Cron("0 0 31 2 *".to_string()), 0).is_err());
+        assert!(next_run_at(&ScheduleTrigger::Interval(10), 0).is_err());
+        assert!(next_run_at(&ScheduleTrigger::Interval(u64::MAX), 0).is_err());
+        assert!(next_run_at(&ScheduleTrigger::Interval(3600), u64::MAX).is_err());
+        assert_eq!(
+            next_run_at(&ScheduleTrigger::Interval(3600), 0),
+            Ok(3600 * NANOS_PER_SEC)
+        );
+    }
+
+    #[test]
+    fn renders_date_in_goal() {
+        let now = to_nanos(datetime!(2024-01-15 09:00 UTC));
+
+        let goal = render_schedule_goal("Summarise IC news of the week up to {{date}}", now);
+
+        assert_eq!(goal, "Summarise IC news of the week up to 2024-01-15");
+    }
+}
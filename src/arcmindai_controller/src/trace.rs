@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use crate::datatype::{CommandTraceStats, StepOutcome, StepTrace, MAX_STEP_TRACE_ARGS_CHARS};
+
+// label of steps whose model response was not a valid command
+const INVALID_COMMAND_LABEL: &str = "(invalid)";
+
+// A step being traced, completed with its latency and cycles when the step ends
+pub struct PendingStepTrace {
+    pub trace: StepTrace,
+    pub started_at: u64,
+    pub start_cycles_balance: u64,
+}
+
+pub fn truncate_trace_args(args: &str) -> String {
+    args.chars().take(MAX_STEP_TRACE_ARGS_CHARS).collect()
+}
+
+// Aggregates traces per command, ordered by command name
+pub fn summarize_step_traces(traces: &[StepTrace]) -> Vec<CommandTraceStats> {
+    let mut stats: BTreeMap<String, CommandTraceStats> = BTreeMap::new();
+
+    for trace in traces {
+        let command = trace
+            .command
+            .clone()
+            .unwrap_or(INVALID_COMMAND_LABEL.to_string());
+        let command_stats = stats
+            .entry(command.clone())
+            .or_insert_with(|| CommandTraceStats {
+                command,
+                num_steps: 0,
+                num_errors: 0,
+                num_recoveries: 0,
+                total_latency_ns: 0,
+                max_latency_ns: 0,
+                total_cycles_used: 0,
+            });
+
+        command_stats.num_steps += 1;
+        match trace.outcome {
+            StepOutcome::Ok => {}
+            StepOutcome::Error => command_stats.num_errors += 1,
+            StepOutcome::Recovery => command_stats.num_recoveries += 1,
+        }
+        command_stats.total_latency_ns += trace.latency_ns;
+        command_stats.max_latency_ns = command_stats.max_latency_ns.max(trace.latency_ns);
+        command_stats.total_cycles_used += trace.cycles_used;
+    }
+
+    stats.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::summarize_step_traces;
+    use crate::datatype::{StepOutcome, StepTrace};
+
+    fn create_trace(command: Option<&str>, outcome: StepOutcome, latency_ns: u64) -> StepTrace {
+        StepTrace {
+            goal_key: 0,
+            step: 0,
+            command: command.map(|c| c.to_string()),
+            args: String::new(),
+            result_size: 0,
+            model: None,
+            latency_ns,
+            cycles_used: 1000,
+            outcome,
+            created_at: 0,
+        }
+    }
+
+    #[test]
+    fn summarizes_traces_per_command() {
+        let traces = vec![
+            create_trace(Some("google"), StepOutcome::Ok, 300),
+            create_trace(Some("google"), StepOutcome::Error, 100),
+            create_trace(None, StepOutcome::Recovery, 50),
+        ];
+
+        let stats = summarize_step_traces(&traces);
+
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].command, "(invalid)");
+        assert_eq!(stats[0].num_recoveries, 1);
+        assert_eq!(stats[1].command, "google");
+        assert_eq!(stats[1].num_steps, 2);
+        assert_eq!(stats[1].num_errors, 1);
+        assert_eq!(stats[1].total_latency_ns, 400);
+        assert_eq!(stats[1].max_latency_ns, 300);
+        assert_eq!(stats[1].total_cycles_used, 2000);
+    }
+}
@@ -0,0 +1,193 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+use crate::datatype::WatchSource;
+
+pub const MIN_WATCH_INTERVAL_SECS: u64 = 5 * 60;
+// content beyond this is not compared, to keep the stored lines small
+const MAX_WATCH_CONTENT_CHARS: usize = 64 * 1024;
+const MAX_WATCH_DIFF_CHARS: usize = 4000;
+
+pub struct WatchDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl WatchDiff {
+    pub fn num_changed_lines(&self) -> usize {
+        self.added.len() + self.removed.len()
+    }
+}
+
+// Text of the first <tag>...</tag> in an XML fragment, without CDATA markers
+fn extract_tag_text(xml: &str, tag: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}", tag))?;
+    let content_start = start + xml[start..].find('>')? + 1;
+    let content_end = content_start + xml[content_start..].find(&format!("</{}>", tag))?;
+
+    let text = xml[content_start..content_end]
+        .trim()
+        .trim_start_matches("<![CDATA[")
+        .trim_end_matches("]]>")
+        .trim();
+    Some(text.to_string())
+}
+
+fn extract_feed_items(content: &str) -> Vec<String> {
+    let item_tag = if content.contains("<item") {
+        "item"
+    } else {
+        "entry"
+    };
+
+    content
+        .split(&format!("<{}", item_tag))
+        .skip(1)
+        .filter_map(|item| {
+            let title = extract_tag_text(item, "title").unwrap_or_default();
+            // Atom links are attributes, RSS links are text
+            let link = extract_tag_text(item, "link")
+                .filter(|link| !link.is_empty())
+                .or_else(|| {
+                    let href_start = item.find("href=\"")? + 6;
+                    let href_end = href_start + item[href_start..].find('"')?;
+                    Some(item[href_start..href_end].to_string())
+                })
+                .unwrap_or_default();
+
+            if title.is_empty() && link.is_empty() {
+                None
+            } else {
+                Some(format!("{} {}", title, link).trim().to_string())
+            }
+        })
+        .collect()
+}
+
+/*
+ * Splits content into the lines compared between polls. Whitespace is collapsed
+ * and empty lines dropped so that layout changes are not seen as changes.
+ * Feeds are compared by their items instead, falling back to lines when no
+ * item is found.
+ */
+pub fn extract_watch_lines(content: &str, source: &WatchSource) -> Vec<String> {
+    let content: String = content.chars().take(MAX_WATCH_CONTENT_CHARS).collect();
+
+    if *source == WatchSource::Feed {
+        let items = extract_feed_items(&content);
+        if !items.is_empty() {
+            return items;
+        }
+    }
+
+    content
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+// Tools answer a failed request with an error message as the content, e.g.
+// "The browse_website resulted into error. RejectionCode: .., Error: .."
+pub fn is_tool_error(content: &str) -> bool {
+    let content = content.trim();
+    content.is_empty() || (content.starts_with("The ") && content.contains(" resulted into error."))
+}
+
+pub fn fingerprint_lines(lines: &[String]) -> String {
+    let hash: [u8; 32] = Sha256::digest(lines.join("\n").as_bytes()).into();
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn diff_lines(old_lines: &[String], new_lines: &[String]) -> WatchDiff {
+    let old_set: HashSet<&String> = old_lines.iter().collect();
+    let new_set: HashSet<&String> = new_lines.iter().collect();
+
+    WatchDiff {
+        added: new_lines
+            .iter()
+            .filter(|line| !old_set.contains(line))
+            .cloned()
+            .collect(),
+        removed: old_lines
+            .iter()
+            .filter(|line| !new_set.contains(line))
+            .cloned()
+            .collect(),
+    }
+}
+
+pub fn format_diff(diff: &WatchDiff) -> String {
+    let lines: Vec<String> = diff
+        .added
+        .iter()
+        .map(|line| format!("+ {}", line))
+        .chain(diff.removed.iter().map(|line| format!("- {}", line)))
+        .collect();
+
+    lines
+        .join("\n")
+        .chars()
+        .take(MAX_WATCH_DIFF_CHARS)
+        .collect()
+}
+
+pub fn render_watcher_goal(template: &str, url: &str, diff: &WatchDiff) -> String {
+    template
+        .replace("{{url}}", url)
+        .replace("{{diff}}", &format_diff(diff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_lines, extract_watch_lines, fingerprint_lines, format_diff, is_tool_error};
+    use crate::datatype::WatchSource;
+
+    #[test]
+    fn diffs_web_page_lines() {
+        let old_lines = extract_watch_lines(
+            "Cash rate  4.35%\n\nNext meeting 6 May",
+            &WatchSource::WebPage,
+        );
+        let new_lines = extract_watch_lines(
+            "Cash rate 4.10%\nNext meeting   6 May\n",
+            &WatchSource::WebPage,
+        );
+
+        let diff = diff_lines(&old_lines, &new_lines);
+
+        assert_eq!(diff.num_changed_lines(), 2);
+        assert_eq!(format_diff(&diff), "+ Cash rate 4.10%\n- Cash rate 4.35%");
+        assert_ne!(fingerprint_lines(&old_lines), fingerprint_lines(&new_lines));
+    }
+
+    #[test]
+    fn extracts_feed_items() {
+        let rss = r#"<rss><channel><title>RBA</title>
+            <item><title><![CDATA[Rate decision]]></title><link>https://rba.gov.au/1</link></item>
+            <item><title>Minutes</title><link>https://rba.gov.au/2</link></item>
+        </channel></rss>"#;
+        let atom = r#"<feed><entry><title>Release</title><link href="https://example.com/r"/></entry></feed>"#;
+
+        assert_eq!(
+            extract_watch_lines(rss, &WatchSource::Feed),
+            vec![
+                "Rate decision https://rba.gov.au/1",
+                "Minutes https://rba.gov.au/2"
+            ]
+        );
+        assert_eq!(
+            extract_watch_lines(atom, &WatchSource::Feed),
+            vec!["Release https://example.com/r"]
+        );
+    }
+
+    #[test]
+    fn detects_failed_polls() {
+        assert!(is_tool_error(""));
+        assert!(is_tool_error(
+            "The browse_website resulted into error. RejectionCode: SysTransient, Error: timeout"
+        ));
+        assert!(!is_tool_error("Cash rate 4.10%"));
+    }
+}
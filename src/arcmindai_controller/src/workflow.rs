@@ -0,0 +1,144 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::datatype::{WorkflowNode, WorkflowRun};
+
+pub const MAX_NUM_WORKFLOW_NODES: usize = 20;
+
+// Checks that node ids are unique, dependencies exist and there is no cycle
+pub fn validate_workflow_nodes(nodes: &[WorkflowNode]) -> Result<(), String> {
+    if nodes.is_empty() {
+        return Err("Workflow has no nodes.".to_string());
+    }
+    if nodes.len() > MAX_NUM_WORKFLOW_NODES {
+        return Err(format!(
+            "Workflow has more than {} nodes.",
+            MAX_NUM_WORKFLOW_NODES
+        ));
+    }
+
+    let mut num_dependencies: HashMap<&str, usize> = HashMap::new();
+    for node in nodes {
+        if node.node_id.is_empty() {
+            return Err("Workflow node id is empty.".to_string());
+        }
+        if num_dependencies
+            .insert(
+                &node.node_id,
+                node.depends_on.iter().collect::<HashSet<_>>().len(),
+            )
+            .is_some()
+        {
+            return Err(format!("Workflow node {} is duplicated.", node.node_id));
+        }
+    }
+
+    for node in nodes {
+        for dependency in &node.depends_on {
+            if !num_dependencies.contains_key(dependency.as_str()) {
+                return Err(format!(
+                    "Workflow node {} depends on unknown node {}.",
+                    node.node_id, dependency
+                ));
+            }
+        }
+    }
+
+    // remove nodes without pending dependencies until none is left, or a cycle remains
+    let mut ready: Vec<&str> = num_dependencies
+        .iter()
+        .filter(|(_, num)| **num == 0)
+        .map(|(node_id, _)| *node_id)
+        .collect();
+    let mut num_visited = 0;
+    while let Some(node_id) = ready.pop() {
+        num_visited += 1;
+        for node in nodes {
+            if node
+                .depends_on
+                .iter()
+                .any(|dependency| dependency == node_id)
+            {
+                let num = num_dependencies.get_mut(node.node_id.as_str()).unwrap();
+                *num -= 1;
+                if *num == 0 {
+                    ready.push(&node.node_id);
+                }
+            }
+        }
+    }
+
+    if num_visited < nodes.len() {
+        return Err("Workflow has a dependency cycle.".to_string());
+    }
+
+    Ok(())
+}
+
+// Nodes not scheduled yet whose dependencies all have a result
+pub fn find_ready_nodes<'a>(
+    nodes: &'a [WorkflowNode],
+    run: &WorkflowRun,
+    results: &HashMap<String, String>,
+) -> Vec<&'a WorkflowNode> {
+    nodes
+        .iter()
+        .filter(|node| {
+            run.nodes
+                .iter()
+                .any(|run_node| run_node.node_id == node.node_id && run_node.goal_key.is_none())
+        })
+        .filter(|node| {
+            node.depends_on
+                .iter()
+                .all(|dependency| results.contains_key(dependency))
+        })
+        .collect()
+}
+
+pub fn render_goal_template(template: &str, results: &HashMap<String, String>) -> String {
+    results
+        .iter()
+        .fold(template.to_string(), |goal, (node_id, result)| {
+            goal.replace(&format!("{{{{{}}}}}", node_id), result)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_goal_template, validate_workflow_nodes};
+    use crate::datatype::WorkflowNode;
+    use std::collections::HashMap;
+
+    fn create_node(node_id: &str, depends_on: Vec<&str>) -> WorkflowNode {
+        WorkflowNode {
+            node_id: node_id.to_string(),
+            goal_template: format!("goal of {}", node_id),
+            depends_on: depends_on.into_iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn validates_workflow_nodes() {
+        let pipeline = vec![
+            create_node("research", vec![]),
+            create_node("summarise", vec!["research"]),
+            create_node("pay", vec!["research", "summarise"]),
+        ];
+        assert!(validate_workflow_nodes(&pipeline).is_ok());
+
+        let cycle = vec![create_node("a", vec!["b"]), create_node("b", vec!["a"])];
+        assert!(validate_workflow_nodes(&cycle).is_err());
+
+        let unknown = vec![create_node("a", vec!["c"])];
+        assert!(validate_workflow_nodes(&unknown).is_err());
+    }
+
+    #[test]
+    fn renders_goal_template() {
+        let results = HashMap::from([("research".to_string(), "ICP news".to_string())]);
+
+        let goal = render_goal_template("Summarise {{research}} in 3 bullets", &results);
+
+        assert_eq!(goal, "Summarise ICP news in 3 bullets");
+    }
+}
@@ -31,6 +31,8 @@ use util::generate_request_id;
 
 const BROWSE_WEBSITE_PROXY_URL: &str = "https://browsewebsite-4gbndkvjta-uc.a.run.app";
 const MAX_NUM_GOOGLE_SEARCH_RESULTS: i32 = 6;
+// transform context of responses kept as they are, e.g. RSS and Atom feeds
+const RAW_TRANSFORM_CONTEXT: &[u8] = b"raw";
 
 // 3 days
 const CYCLES_BALANCE_CHECK_MIN_INTERVAL_SECS: u64 = 60 * 60 * 24 * 3;
@@ -50,6 +52,22 @@ pub struct State {
     pub battery_canister: Option<Principal>,
 }
 
+// HTTP outcalls and cycles spent by the tools on a request.
+// Same shape as the usage of the brain, the tools spend no tokens.
+#[derive(CandidType, Serialize, Deserialize, Default)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub http_outcalls: u64,
+    pub cycles_used: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ToolResult {
+    pub content: String,
+    pub usage: Usage,
+}
+
 // Mutable global state
 thread_local! {
     static STATE: RefCell<State> = RefCell::default();
@@ -59,6 +77,26 @@ thread_local! {
 #[update(guard = "assert_owner")]
 #[candid_method(update)]
 async fn browse_website(url: String) -> String {
+    browse_website_with_usage(url).await.content
+}
+
+// same as browse_website, also returning the HTTP outcalls and cycles spent
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn browse_website_with_usage(url: String) -> ToolResult {
+    request_website(url, Vec::new()).await
+}
+
+// same as browse_website_with_usage, without converting HTML to text
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn fetch_website_with_usage(url: String) -> ToolResult {
+    request_website(url, RAW_TRANSFORM_CONTEXT.to_vec()).await
+}
+
+async fn request_website(url: String, transform_context: Vec<u8>) -> ToolResult {
+    let start_cycles_balance: u64 = ic_cdk::api::canister_balance();
+
     let request_id = generate_request_id();
     let headers = vec![
         HttpHeader {
@@ -89,10 +127,10 @@ async fn browse_website(url: String) -> String {
         method: HttpMethod::POST,
         headers,
         body: None,
-        transform: Some(TransformContext::new(transform, vec![])),
+        transform: Some(TransformContext::new(transform, transform_context)),
     };
 
-    match http_request(request).await {
+    let content: String = match http_request(request).await {
         Ok((response,)) => {
             let result = String::from_utf8(response.body)
                 .expect("Transformed response is not UTF-8 encoded.");
@@ -108,13 +146,24 @@ async fn browse_website(url: String) -> String {
 
             message
         }
-    }
+    };
+
+    create_tool_result(content, start_cycles_balance)
 }
 
 // entry function for user to perform google search on a query
 #[update(guard = "assert_owner")]
 #[candid_method(update)]
 async fn google(query: String) -> String {
+    google_with_usage(query).await.content
+}
+
+// same as google, also returning the HTTP outcalls and cycles spent
+#[update(guard = "assert_owner")]
+#[candid_method(update)]
+async fn google_with_usage(query: String) -> ToolResult {
+    let start_cycles_balance: u64 = ic_cdk::api::canister_balance();
+
     ic_cdk::api::print(format!(
         "\n ------------- Google Search -------------\n{:?}",
         query
@@ -164,7 +213,7 @@ async fn google(query: String) -> String {
         transform: Some(TransformContext::new(transform, vec![])),
     };
 
-    match http_request(request).await {
+    let content: String = match http_request(request).await {
         Ok((response,)) => {
             let result = String::from_utf8(response.body)
                 .expect("Transformed response is not UTF-8 encoded.");
@@ -177,6 +226,21 @@ async fn google(query: String) -> String {
 
             message
         }
+    };
+
+    create_tool_result(content, start_cycles_balance)
+}
+
+// Each tool makes one HTTP outcall, its cycles are read from the balance ignoring top-ups.
+// They are approximate, requests handled while awaiting the outcall are counted too.
+fn create_tool_result(content: String, start_cycles_balance: u64) -> ToolResult {
+    ToolResult {
+        content,
+        usage: Usage {
+            http_outcalls: 1,
+            cycles_used: start_cycles_balance.saturating_sub(ic_cdk::api::canister_balance()),
+            ..Default::default()
+        },
     }
 }
 
@@ -187,6 +251,11 @@ fn transform(args: TransformArgs) -> HttpResponse {
         ..Default::default()
     };
 
+    if res.status == 200 && args.context == RAW_TRANSFORM_CONTEXT {
+        res.body = args.response.body;
+        return res;
+    }
+
     if res.status == 200 {
         let mut res_str = String::from_utf8(args.response.body.clone())
             .expect("Transformed response is not UTF-8 encoded.");
@@ -400,6 +469,7 @@ fn post_upgrade(
 // ---------------------- Candid declarations did file generator ----------------------
 #[cfg(test)]
 mod tests {
+    use crate::ToolResult;
     use candid::{export_service, Principal};
 
     #[test]